@@ -8,12 +8,16 @@ use hyperware_process_lib::{
     println,
     homepage::add_to_homepage,
     http::server::{send_ws_push, WsMessageType},
+    http::StatusCode,
     vfs,
     LazyLoadBlob,
     Address,
     ProcessId,
     Request,
-    hyperapp::{SaveOptions, send, sleep, spawn},
+    hyperapp::{
+        SaveOptions, send, sleep, spawn, source,
+        get_path, get_request_header, add_response_header, set_response_status, get_server,
+    },
 };
 use serde::{Deserialize, Serialize, Deserializer, Serializer};
 use serde_json;
@@ -25,6 +29,10 @@ use flate2::Compression;
 use std::io::{Write, Read};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use sha2::{Digest, Sha256};
+use hmac::{Hmac, Mac};
+
+type HmacSha256 = Hmac<Sha256>;
 
 // Import generated RPC functions from caller-utils
 use chat_caller_utils::chat::{
@@ -34,6 +42,15 @@ use chat_caller_utils::chat::{
     receive_message_deletion_remote_rpc,
     receive_reaction_remote_rpc,
     receive_profile_update_remote_rpc,
+    receive_disappearing_timer_remote_rpc,
+    receive_pin_update_remote_rpc,
+    upgrade_guest_chat_remote_rpc,
+    enforce_retention_local_rpc,
+    expire_messages_local_rpc,
+    release_scheduled_messages_local_rpc,
+    release_pending_message_local_rpc,
+    flush_notifications_local_rpc,
+    send_notification_digest_local_rpc,
 };
 use chat_caller_utils::ChatMessage as CUChatMessage;
 use chat_caller_utils::UserProfile as CUUserProfile;
@@ -93,6 +110,8 @@ pub struct ChatMessage {
     pub reactions: Vec<MessageReaction>,
     pub message_type: MessageType,
     pub file_info: Option<FileInfo>,
+    #[serde(default)]
+    pub expires_at: Option<u64>, // Set for disappearing messages; both sides delete it after this time
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -108,6 +127,7 @@ pub enum MessageType {
     Image,
     File,
     VoiceNote,
+    System, // Local timeline events, never sent to the counterparty
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -118,8 +138,37 @@ pub struct FileInfo {
     pub url: String, // VFS path or data URL
 }
 
+// Index entry for a file stored in our VFS, used when serving /files/*
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredFile {
+    pub file_id: String,
+    pub chat_id: String,
+    pub message_id: String,
+    pub filename: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub vfs_path: String,
+    pub etag: String,
+    pub created_at: u64,
+    #[serde(default)]
+    pub hash: String, // SHA-256 of the content; empty for files stored before deduplication
+    #[serde(default)]
+    pub sender: String,
+}
+
+// File content stored once under /files/blobs/<hash> and shared by every StoredFile with that hash
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredBlob {
+    pub hash: String,
+    pub size: u64,
+    pub vfs_path: String,
+    pub ref_count: u32,
+    pub created_at: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum MessageStatus {
+    Pending, // Held locally during the undo-send grace period
     Sending,
     Sent,
     Delivered,
@@ -137,6 +186,84 @@ pub struct Chat {
     pub notify: bool,
     #[serde(default)]
     pub counterparty_profile: Option<UserProfile>,
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>, // Overrides the global retention policy
+    #[serde(default)]
+    pub disappearing_timer: Option<u64>, // Seconds until new messages expire, agreed by both sides
+    #[serde(default)]
+    pub disappearing_timer_updated_at: u64,
+    #[serde(default)]
+    pub pinned_messages: Vec<PinnedMessage>, // Shared with the counterparty
+    #[serde(default)]
+    pub is_archived: bool,
+    #[serde(default)]
+    pub muted_until: Option<u64>, // Timed mute; `notify: false` mutes indefinitely
+    #[serde(default)]
+    pub labels: Vec<String>, // Label ids, local only
+    #[serde(default)]
+    pub counterparty_nickname: Option<String>, // Mirrors the contact's local nickname
+    #[serde(default)]
+    pub is_request: bool, // Unsolicited chat from an unknown node awaiting accept/decline
+    #[serde(default)]
+    pub low_priority: bool, // Goes into the notification digest when digests are on
+    #[serde(default)]
+    pub is_guest: bool, // Counterparty is a browser guest reached over WebSocket, not a node
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PinnedMessage {
+    pub message_id: String,
+    pub pinned_by: String,
+    pub pinned_at: u64,
+}
+
+impl Chat {
+    // An empty chat with every optional setting off; callers override fields with struct update syntax
+    fn new(id: String, counterparty: String, last_activity: u64) -> Self {
+        Chat {
+            id,
+            counterparty,
+            messages: Vec::new(),
+            last_activity,
+            unread_count: 0,
+            is_blocked: false,
+            notify: true,
+            counterparty_profile: None,
+            retention: None,
+            disappearing_timer: None,
+            disappearing_timer_updated_at: 0,
+            pinned_messages: Vec::new(),
+            is_archived: false,
+            muted_until: None,
+            labels: Vec::new(),
+            counterparty_nickname: None,
+            is_request: false,
+            low_priority: false,
+            is_guest: false,
+        }
+    }
+
+    fn is_pinned(&self, message_id: &str) -> bool {
+        self.pinned_messages.iter().any(|p| p.message_id == message_id)
+    }
+
+    fn is_muted(&self, now: u64) -> bool {
+        !self.notify || self.muted_until.map_or(false, |until| until > now)
+    }
+}
+
+// How long message history is kept. Unset limits mean keep forever.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct RetentionPolicy {
+    pub max_age_days: Option<u32>,
+    pub max_messages: Option<u32>,
+    pub attachments_only: bool, // Remove expired attachments but keep the message text
+}
+
+impl RetentionPolicy {
+    fn is_unlimited(&self) -> bool {
+        self.max_age_days.is_none() && self.max_messages.is_none()
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -146,6 +273,114 @@ pub struct ChatKey {
     pub created_at: u64,
     pub is_revoked: bool,
     pub chat_id: String,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub max_uses: Option<u32>, // Distinct guest devices allowed; single-use links allow one
+    #[serde(default)]
+    pub claimed_by: Vec<String>, // Device ids of the guests that have used the link
+    #[serde(default)]
+    pub passphrase_hash: Option<String>, // Salted hash, see hash_passphrase
+    #[serde(default)]
+    pub name_locked: bool, // Owner picked the guest name, so the guest can't change it
+    #[serde(default)]
+    pub uploaded_bytes: u64, // Counted against guest_upload_quota_mb
+    #[serde(default)]
+    pub is_banned: bool, // Revoked by a ban; the chat and its history are kept
+    #[serde(default)]
+    pub is_widget: bool, // Provisioned for a website visitor rather than created by the owner
+    #[serde(default)]
+    pub visitor_email: Option<String>, // From the widget's pre-chat form
+    #[serde(default)]
+    pub away_replied_at: Option<u64>,
+    #[serde(default)]
+    pub upgraded_to: Option<String>, // Node the guest moved the conversation to
+}
+
+impl ChatKey {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= now)
+    }
+
+    fn is_usable(&self, now: u64) -> bool {
+        !self.is_revoked && !self.is_expired(now)
+    }
+}
+
+// A browser tab connected through a chat link; a key can have several
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GuestSession {
+    pub channel_id: u32,
+    pub chat_key: String,
+    pub chat_id: String,
+    pub display_name: String,
+    pub device_id: Option<String>,
+    pub connected_at: u64,
+    pub last_message: Option<String>, // Preview of the last message sent from this tab
+    pub last_message_at: Option<u64>,
+    pub message_count: u32,
+}
+
+// Lets a guest tab reconnect with Resume instead of authenticating and reloading the whole chat
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GuestResumeToken {
+    pub chat_key: String,
+    pub device_id: Option<String>,
+    pub issued_at: u64,
+    #[serde(default)]
+    pub synced_at: u64, // When the tab last received the chat's history
+}
+
+// A guest's claim to run `node`, proven when that node calls upgrade_guest_chat with the challenge
+#[derive(Clone, Debug)]
+struct GuestUpgrade {
+    chat_key: String,
+    node: String,
+    created_at: u64,
+}
+
+// Passphrases are stored as "<salt>$<HMAC-SHA256 of key and passphrase keyed by the salt>", in hex
+fn hash_passphrase(key: &str, passphrase: &str) -> String {
+    let salt = format!("{:032x}", rand::random::<u128>());
+    let mac = passphrase_mac(&salt, key, passphrase);
+    format!("{}${:x}", salt, mac.finalize().into_bytes())
+}
+
+fn passphrase_mac(salt: &str, key: &str, passphrase: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(salt.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(key.as_bytes());
+    mac.update(passphrase.as_bytes());
+    mac
+}
+
+// Compares in constant time, so response timing doesn't leak how close a guess was
+fn verify_passphrase(key: &str, passphrase: &str, stored: &str) -> bool {
+    match stored.split_once('$') {
+        Some((salt, expected)) => decode_hex(expected)
+            .map_or(false, |expected| passphrase_mac(salt, key, passphrase).verify_slice(&expected).is_ok()),
+        // Links created before passphrases were salted hold a plain SHA-256 of key and passphrase
+        None => {
+            let mut hasher = Sha256::new();
+            hasher.update(key.as_bytes());
+            hasher.update(passphrase.as_bytes());
+            decode_hex(stored).map_or(false, |expected| constant_time_eq(&hasher.finalize(), &expected))
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -154,6 +389,17 @@ pub struct UserProfile {
     pub profile_pic: Option<String>,
 }
 
+// Local address book entry; nothing here is shared with the contact
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Contact {
+    pub node: String,
+    pub nickname: Option<String>, // Overrides the name from their profile
+    pub notes: String,
+    pub favorite: bool,
+    pub profile: Option<UserProfile>, // Last profile the node pushed to us
+    pub added_at: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Settings {
     pub show_images: bool,
@@ -166,6 +412,164 @@ pub struct Settings {
     pub stt_enabled: bool,
     pub stt_api_key: Option<String>,
     pub max_file_size_mb: u64,
+    #[serde(default = "default_inbound_quota_mb")]
+    pub inbound_quota_mb: u64, // Max attachment storage per counterparty, 0 = unlimited
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    #[serde(default)]
+    pub undo_send_seconds: u32, // Grace period before outgoing messages leave the node, 0 = off
+    #[serde(default)]
+    pub keep_archived: bool, // Don't unarchive chats when a new message arrives
+    #[serde(default)]
+    pub message_requests: bool, // Hold chats from unknown nodes in the requests inbox
+    #[serde(default)]
+    pub notification_rules: NotificationRules,
+    #[serde(default)]
+    pub public_base_url: Option<String>, // Scheme and host for chat links, e.g. https://chat.example.com
+    #[serde(default = "default_guest_upload_quota_mb")]
+    pub guest_upload_quota_mb: u64, // Total a single chat link may upload, 0 = unlimited
+    #[serde(default)]
+    pub widget: WidgetSettings,
+}
+
+fn default_guest_upload_quota_mb() -> u64 {
+    50
+}
+
+// Daily window (local time) in which pushes are held back; wraps past midnight when start > end
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct QuietHours {
+    pub start_minute: u16, // Minutes after local midnight
+    pub end_minute: u16,
+    pub days: Vec<u8>, // 0 = Sunday .. 6 = Saturday, empty = every day
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ContactNotificationOverride {
+    pub node: String,
+    pub enabled: Option<bool>, // Some(true) notifies even when the chat or app notifications are off
+    pub bypass_quiet_hours: bool,
+    pub hide_preview: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NotificationRules {
+    pub quiet_hours: Vec<QuietHours>,
+    pub tz_offset_minutes: i32, // Local time = UTC + offset
+    pub keywords: Vec<String>, // Matched case-insensitively; break through mutes and quiet hours
+    pub hide_preview: bool,
+    pub contact_overrides: Vec<ContactNotificationOverride>,
+    #[serde(default = "default_coalesce_window_seconds")]
+    pub coalesce_window_seconds: u32, // Later pushes for a chat within this window are merged, 0 = off
+    #[serde(default)]
+    pub digest_interval_minutes: u32, // Summarise muted and low-priority chats periodically, 0 = off
+}
+
+fn default_coalesce_window_seconds() -> u32 {
+    60
+}
+
+impl Default for NotificationRules {
+    fn default() -> Self {
+        NotificationRules {
+            quiet_hours: Vec::new(),
+            tz_offset_minutes: 0,
+            keywords: Vec::new(),
+            hide_preview: false,
+            contact_overrides: Vec::new(),
+            coalesce_window_seconds: default_coalesce_window_seconds(),
+            digest_interval_minutes: 0,
+        }
+    }
+}
+
+// What to do with an incoming message as far as push notifications go
+enum NotifyDecision {
+    Silent,
+    Digest,
+    Push { sender_name: String, body: String },
+}
+
+// Pushes for a chat after the first one inside the coalescing window are held here
+#[derive(Clone, Debug)]
+struct NotificationWindow {
+    opened_at: u64, // Also identifies the window to the flush task scheduled when it opened
+    held: u32,
+    sender: String,
+    sender_name: String,
+    last_body: String,
+}
+
+impl NotificationRules {
+    fn in_quiet_hours(&self, now: u64) -> bool {
+        in_daily_windows(&self.quiet_hours, self.tz_offset_minutes, now)
+    }
+
+    fn matches_keyword(&self, content: &str) -> bool {
+        let content = content.to_lowercase();
+        self.keywords.iter()
+            .map(|k| k.trim().to_lowercase())
+            .any(|k| !k.is_empty() && content.contains(&k))
+    }
+
+    fn contact_override(&self, node: &str) -> Option<&ContactNotificationOverride> {
+        self.contact_overrides.iter().find(|o| o.node == node)
+    }
+}
+
+fn in_daily_windows(windows: &[QuietHours], tz_offset_minutes: i32, now: u64) -> bool {
+    let local = now as i64 + tz_offset_minutes as i64 * 60;
+    let minute = (local.rem_euclid(86_400) / 60) as u16;
+    // 1970-01-01 was a Thursday
+    let weekday = ((local.div_euclid(86_400) + 4).rem_euclid(7)) as u8;
+    let yesterday = (weekday + 6) % 7;
+
+    windows.iter().any(|q| {
+        let on = |day: u8| q.days.is_empty() || q.days.contains(&day);
+        if q.start_minute <= q.end_minute {
+            on(weekday) && minute >= q.start_minute && minute < q.end_minute
+        } else {
+            // Overnight window belongs to the day it started on
+            (on(weekday) && minute >= q.start_minute) || (on(yesterday) && minute < q.end_minute)
+        }
+    })
+}
+
+// Website widget that gives every visitor their own guest chat
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct WidgetSettings {
+    pub enabled: bool,
+    pub allowed_origins: Vec<String>, // Sites allowed to embed the widget, e.g. https://example.com
+    pub title: Option<String>,
+    pub greeting: Option<String>, // Shown above the pre-chat form
+    pub ask_name: bool,
+    pub ask_email: bool,
+    pub office_hours: Vec<QuietHours>, // Same daily windows as quiet hours; empty = always open
+    pub tz_offset_minutes: i32,
+    pub away_message: Option<String>, // Auto-reply to visitors writing outside office hours
+}
+
+impl WidgetSettings {
+    fn is_open(&self, now: u64) -> bool {
+        self.office_hours.is_empty() || in_daily_windows(&self.office_hours, self.tz_offset_minutes, now)
+    }
+}
+
+// What the widget page needs to render before the visitor has a chat
+#[derive(Serialize, Clone, Debug)]
+struct WidgetPageConfig {
+    title: String,
+    greeting: Option<String>,
+    ask_name: bool,
+    ask_email: bool,
+    open: bool,
+    away_message: Option<String>,
+    ticket: String, // Required by WidgetStart, see widget_ticket
+}
+
+fn default_inbound_quota_mb() -> u64 {
+    500
 }
 
 impl Default for Settings {
@@ -181,6 +585,15 @@ impl Default for Settings {
             stt_enabled: false,
             stt_api_key: None,
             max_file_size_mb: 10, // Default 10MB limit
+            inbound_quota_mb: default_inbound_quota_mb(),
+            retention: RetentionPolicy::default(),
+            undo_send_seconds: 0,
+            keep_archived: false,
+            message_requests: false,
+            notification_rules: NotificationRules::default(),
+            public_base_url: None,
+            guest_upload_quota_mb: default_guest_upload_quota_mb(),
+            widget: WidgetSettings::default(),
         }
     }
 }
@@ -204,16 +617,50 @@ pub enum WsClientMessage {
     UpdateStatus {
         status: String
     },
+    GetChats(GetChatsReq),
+    GetLabels,
+    CreateLabel(CreateLabelReq),
+    UpdateLabel(UpdateLabelReq),
+    DeleteLabel(DeleteLabelReq),
+    SetChatLabels(SetChatLabelsReq),
 
-    // Browser chat messages
+    // Common
+    Heartbeat,
+}
+
+// The only messages accepted on the public guest socket
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum WsGuestMessage {
     AuthWithKey {
-        chat_key: String
+        chat_key: String,
+        #[serde(default)]
+        device_id: Option<String>, // Random id the guest page keeps in localStorage
+        #[serde(default)]
+        passphrase: Option<String>,
+        #[serde(default)]
+        display_name: Option<String>,
     },
     BrowserMessage {
         content: String
     },
-
-    // Common
+    SendFile {
+        filename: String,
+        mime_type: String,
+        data: String, // Base64
+    },
+    Resume {
+        session_token: String,
+        since_message_id: Option<String>, // Last message the guest has
+    },
+    WidgetStart {
+        ticket: String, // From the widget page's WIDGET_CONFIG
+        device_id: Option<String>,
+        name: Option<String>,
+        email: Option<String>,
+    },
+    RequestUpgrade {
+        node: String,
+    },
     Heartbeat,
 }
 
@@ -229,19 +676,59 @@ pub enum WsServerMessage {
         status: String
     },
     ChatUpdate(Chat),
+    ChatList(Vec<Chat>),
+    LabelsUpdate(Vec<Label>),
+    ContactsUpdate(Vec<Contact>),
+    StarredUpdate(Vec<StarredMessage>),
     ProfileUpdate {
         node: String,
         profile: UserProfile,
     },
+    ScheduledMessagesUpdate(Vec<ScheduledMessage>),
+    ScheduledMessageSent {
+        chat_id: String,
+        message: ChatMessage,
+    },
+    ScheduledMessageFailed {
+        id: String,
+        chat_id: String,
+        error: String,
+    },
 
     // Browser chat messages
     AuthSuccess {
         chat_id: String,
-        history: Vec<ChatMessage>
+        history: Vec<ChatMessage>,
+        session_token: String,
+    },
+    Resumed {
+        chat_id: String,
+        messages: Vec<ChatMessage>, // Messages after since_message_id
+        full_history: bool, // since_message_id wasn't found, so messages is the whole chat
     },
     AuthFailed {
         reason: String
     },
+    Kicked {
+        reason: String
+    },
+    WidgetStarted {
+        chat_key: String, // Kept by the visitor's browser to come back to the same chat
+    },
+    UpgradeChallenge {
+        host: String, // Our node, which the guest's node has to send the challenge to
+        node: String,
+        challenge: String,
+        expires_at: u64,
+    },
+    Upgraded {
+        node: String,
+        chat_id: String,
+    },
+    ChatMerged {
+        from_chat_id: String,
+        into_chat_id: String,
+    },
 
     // Common
     Heartbeat,
@@ -294,6 +781,8 @@ pub struct SendMessageReq {
     pub content: String,
     pub reply_to: Option<String>,
     pub file_info: Option<FileInfo>,
+    #[serde(default)]
+    pub send_at: Option<u64>, // Schedule for later (unix seconds)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -335,6 +824,14 @@ pub struct ForwardMessageReq {
 pub struct CreateChatLinkReq {
     pub chat_id: String,
     pub single_use: bool,
+    #[serde(default)]
+    pub expires_in_seconds: Option<u64>,
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    #[serde(default)]
+    pub guest_name: Option<String>, // None lets the guest pick their own name
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -342,6 +839,67 @@ pub struct RevokeChatKeyReq {
     pub key: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KickGuestSessionReq {
+    pub channel_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BanChatKeyReq {
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum QrFormat {
+    Png,
+    Svg,
+}
+
+impl Default for QrFormat {
+    fn default() -> Self {
+        QrFormat::Png
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum QrErrorCorrection {
+    Low,
+    Medium,
+    Quartile,
+    High,
+}
+
+impl Default for QrErrorCorrection {
+    fn default() -> Self {
+        QrErrorCorrection::Medium
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct QrCodeReq {
+    pub link: Option<String>, // None renders our own "message me" invite: the widget page, or our node name
+    pub format: QrFormat,
+    pub size: Option<u32>, // Target width in pixels; rounded down to whole modules
+    pub error_correction: QrErrorCorrection,
+    pub with_icon: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QrCodeImage {
+    pub link: String,
+    pub mime_type: String,
+    pub size: u32,
+    pub error_correction: QrErrorCorrection, // Raised to Quartile when the icon would hide too much
+    pub data_url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClaimGuestChatReq {
+    pub host: String, // Node whose chat link we used as a guest
+    pub challenge: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UploadFileReq {
     pub chat_id: String,
@@ -349,6 +907,8 @@ pub struct UploadFileReq {
     pub mime_type: String,
     pub data: String, // base64 encoded
     pub reply_to: Option<String>,
+    #[serde(default)]
+    pub send_at: Option<u64>, // Schedule for later (unix seconds)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -363,47 +923,457 @@ pub struct SendVoiceNoteReq {
     pub audio_data: String, // base64 encoded
     pub duration: u32, // in seconds
     pub reply_to: Option<String>,
+    #[serde(default)]
+    pub send_at: Option<u64>, // Schedule for later (unix seconds)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct SearchChatsReq {
-    pub query: String,
-}
-
-// just the ones we care about
-#[derive(Serialize, Deserialize, Clone, Debug, process_macros::SerdeJsonInto)]
-enum HomepageRequest {
-    GetPushSubscription
+pub struct SetChatRetentionReq {
+    pub chat_id: String,
+    pub retention: Option<RetentionPolicy>, // None = use the global policy
 }
 
-// just the ones we care about
-#[derive(Serialize, Deserialize, Clone, Debug, process_macros::SerdeJsonInto)]
-enum HomepageResponse {
-    PushSubscription(Option<String>)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetDisappearingTimerReq {
+    pub chat_id: String,
+    pub seconds: Option<u64>, // None turns disappearing messages off
 }
 
-// APP STATE
-
-#[derive(Serialize, Deserialize)]
-pub struct ChatState {
-    pub profile: UserProfile,
-    pub chats: HashMap<String, Chat>,
-    pub chat_keys: HashMap<String, ChatKey>,
-    pub settings: Settings,
-    #[serde(with = "arc_mutex_serde")]
-    pub delivery_queue: Arc<Mutex<HashMap<String, Vec<ChatMessage>>>>,
-    pub online_nodes: HashSet<String>,
-    pub ws_connections: HashMap<u32, String>, // channel_id -> node/browser_id
-    pub browser_connections: HashMap<String, u32>, // chat_key -> channel_id
-    pub last_heartbeat: HashMap<u32, u64>, // channel_id -> timestamp
-    #[serde(default)]
-    pub active_connections: HashSet<u32>, // channel_ids that are actively viewing the app
+// A message waiting in the scheduler. Files are stored when scheduled and referenced by
+// file_id; voice notes keep their base64 data until sent.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ScheduledMessage {
+    pub id: String, // Becomes the message ID once sent
+    pub chat_id: String,
+    pub send_at: u64,
+    pub created_at: u64,
+    pub message_type: MessageType,
+    pub content: String,
+    pub reply_to: Option<String>,
+    pub filename: Option<String>,
+    pub mime_type: Option<String>,
+    pub data: Option<String>,
+    pub duration: Option<u32>,
     #[serde(default)]
-    pub node_profiles: HashMap<String, UserProfile>, // Store profiles of other nodes
+    pub file_id: Option<String>,
 }
 
-fn default_delivery_queue() -> Arc<Mutex<HashMap<String, Vec<ChatMessage>>>> {
-    Arc::new(Mutex::new(HashMap::new()))
+impl ScheduledMessage {
+    // What the message will look like once sent
+    fn preview(&self) -> ChatMessage {
+        ChatMessage {
+            id: self.id.clone(),
+            sender: our().node.clone(),
+            content: self.content.clone(),
+            timestamp: self.send_at,
+            status: MessageStatus::Sending,
+            reply_to: self.reply_to.clone(),
+            reactions: Vec::new(),
+            message_type: self.message_type.clone(),
+            file_info: None,
+            expires_at: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetScheduledMessagesReq {
+    pub chat_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EditScheduledMessageReq {
+    pub id: String,
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RescheduleMessageReq {
+    pub id: String,
+    pub send_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CancelScheduledMessageReq {
+    pub id: String,
+}
+
+// An outgoing message waiting out the undo-send grace period, in the form it will be sent
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingSend {
+    pub chat_id: String,
+    pub message: ChatMessage,
+    pub release_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CancelPendingMessageReq {
+    pub chat_id: String,
+    pub message_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PinMessageReq {
+    pub chat_id: String,
+    pub message_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetPinnedReq {
+    pub chat_id: String,
+}
+
+// User-defined folder/label for organising chats
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Label {
+    pub id: String,
+    pub name: String,
+    pub color: Option<String>,
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ChatSort {
+    LastActivity,
+    UnreadCount,
+    Name,
+}
+
+impl Default for ChatSort {
+    fn default() -> Self {
+        ChatSort::LastActivity
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct GetChatsReq {
+    pub label: Option<String>,  // Only chats carrying this label id
+    pub unread_only: bool,
+    pub archived: Option<bool>, // None hides archived chats
+    pub blocked: Option<bool>,  // None includes blocked chats
+    pub requests: Option<bool>, // None hides message requests; Some(true) lists only the requests inbox
+    pub sort: Option<ChatSort>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreateLabelReq {
+    pub name: String,
+    pub color: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateLabelReq {
+    pub label_id: String,
+    pub name: Option<String>,
+    pub color: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeleteLabelReq {
+    pub label_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SetChatLabelsReq {
+    pub chat_id: String,
+    pub label_ids: Vec<String>,
+}
+
+// Private bookmark on a message; kept apart from ChatMessage so it is never sent anywhere
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StarredMessage {
+    pub chat_id: String,
+    pub message_id: String,
+    pub starred_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StarMessageReq {
+    pub chat_id: String,
+    pub message_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetStarredReq {
+    pub before_starred_at: Option<u64>,
+    #[serde(default)]
+    pub before_message_id: Option<String>, // Breaks ties between stars made in the same second
+    pub limit: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StarredMessageEntry {
+    pub chat_id: String,
+    pub counterparty: String,
+    pub starred_at: u64,
+    pub message: ChatMessage,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetMessageContextReq {
+    pub chat_id: String,
+    pub message_id: String,
+    pub radius: Option<u64>, // Messages to include on each side, default 20
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageContext {
+    pub chat_id: String,
+    pub messages: Vec<ChatMessage>,
+    pub target_index: u32, // Position of the requested message in `messages`
+    pub has_older: bool,
+    pub has_newer: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveChatReq {
+    pub chat_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetChatPriorityReq {
+    pub chat_id: String,
+    pub low_priority: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MuteChatReq {
+    pub chat_id: String,
+    pub until: Option<u64>, // Unix seconds; None mutes until unmuted
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnmuteChatReq {
+    pub chat_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MessageRequestReq {
+    pub chat_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddContactReq {
+    pub node: String,
+    pub nickname: Option<String>,
+    pub notes: Option<String>,
+    pub favorite: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpdateContactReq {
+    pub node: String,
+    pub nickname: Option<String>, // Empty string clears the nickname
+    pub notes: Option<String>,
+    pub favorite: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoveContactReq {
+    pub node: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchContactsReq {
+    pub query: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchChatsReq {
+    pub query: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatStorageUsage {
+    pub chat_id: String,
+    pub counterparty: String,
+    pub file_count: u32,
+    pub file_bytes: u64,   // Attachments stored in VFS (shared content counted in every chat using it)
+    pub inline_bytes: u64, // Data URLs kept in message history (images, voice notes)
+    pub message_count: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MediaTypeUsage {
+    pub media_type: String, // image, audio, video, document, other
+    pub file_count: u32,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StorageUsage {
+    pub disk_bytes: u64, // Unique content actually stored in VFS
+    pub referenced_bytes: u64, // Sum over all references, i.e. what we'd use without deduplication
+    pub blob_count: u32,
+    pub chats: Vec<ChatStorageUsage>,
+    pub media_types: Vec<MediaTypeUsage>,
+}
+
+// INBOUND LIMITS
+
+// Traffic classes that get their own token bucket per node, guest link or widget ticket
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum InboundKind {
+    Message,
+    Reaction,
+    Profile,
+    GuestMessage,
+    WidgetStart,
+    PassphraseAttempt, // Wrong passphrases for one chat link
+    ChatSetting, // Disappearing timer and pin changes
+}
+
+impl InboundKind {
+    // (bucket capacity, tokens refilled per second)
+    fn limits(self) -> (f64, f64) {
+        match self {
+            InboundKind::Message => (30.0, 2.0),
+            InboundKind::Reaction => (30.0, 2.0),
+            InboundKind::Profile => (5.0, 1.0 / 60.0),
+            InboundKind::GuestMessage => (10.0, 0.5),
+            InboundKind::WidgetStart => (20.0, 0.5), // Shared by every widget page
+            InboundKind::PassphraseAttempt => (5.0, 1.0 / 60.0),
+            InboundKind::ChatSetting => (20.0, 0.5),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct RateBucket {
+    tokens: f64,
+    refilled_at_ms: u64,
+}
+
+impl RateBucket {
+    fn new(kind: InboundKind, now_ms: u64) -> Self {
+        RateBucket { tokens: kind.limits().0, refilled_at_ms: now_ms }
+    }
+
+    // Add the tokens earned since the last refill
+    fn refill(&mut self, kind: InboundKind, now_ms: u64) {
+        let (capacity, per_second) = kind.limits();
+        let elapsed = now_ms.saturating_sub(self.refilled_at_ms) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * per_second).min(capacity);
+        self.refilled_at_ms = now_ms;
+    }
+
+    // How long until a whole token is available
+    fn retry_after_ms(&self, kind: InboundKind) -> u64 {
+        let (_, per_second) = kind.limits();
+        ((1.0 - self.tokens).max(0.0) / per_second * 1000.0).ceil() as u64
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum InboundViolation {
+    RateLimited,
+    ContentTooLong,
+    EmojiTooLong,
+    TooManyReactions,
+    ProfileNameTooLong,
+    ProfilePicTooLarge,
+    AttachmentTooLarge,
+    SenderMismatch, // Payload sender is not the calling node
+    TimestampInFuture,
+}
+
+// Returned to the sending node as the JSON error string of the rejected call
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InboundRejection {
+    pub violation: InboundViolation,
+    pub detail: String,
+    pub retry_after_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ViolationCount {
+    pub node: String,
+    pub violation: InboundViolation,
+    pub count: u64,
+    pub last_seen: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Diagnostics {
+    pub total_violations: u64,
+    pub inbound_violations: Vec<ViolationCount>, // Most frequent first
+    pub delivery_queue_len: u32,
+    pub online_nodes: u32,
+    pub ws_connections: u32,
+}
+
+// just the ones we care about
+#[derive(Serialize, Deserialize, Clone, Debug, process_macros::SerdeJsonInto)]
+enum HomepageRequest {
+    GetPushSubscription
+}
+
+// just the ones we care about
+#[derive(Serialize, Deserialize, Clone, Debug, process_macros::SerdeJsonInto)]
+enum HomepageResponse {
+    PushSubscription(Option<String>)
+}
+
+// APP STATE
+
+#[derive(Serialize, Deserialize)]
+pub struct ChatState {
+    pub profile: UserProfile,
+    pub chats: HashMap<String, Chat>,
+    pub chat_keys: HashMap<String, ChatKey>,
+    pub settings: Settings,
+    #[serde(with = "arc_mutex_serde")]
+    pub delivery_queue: Arc<Mutex<HashMap<String, Vec<ChatMessage>>>>,
+    pub online_nodes: HashSet<String>,
+    pub ws_connections: HashMap<u32, String>, // channel_id -> node/browser_id
+    #[serde(skip)]
+    pub guest_sessions: HashMap<u32, GuestSession>, // channel_id -> guest tab
+    #[serde(default)]
+    pub resume_tokens: HashMap<String, GuestResumeToken>, // token -> guest device
+    #[serde(default)]
+    pub history_changed_at: HashMap<String, u64>, // chat_id -> last edit, deletion or reaction
+    #[serde(skip)]
+    guest_upgrades: HashMap<String, GuestUpgrade>, // challenge -> pending upgrade
+    pub last_heartbeat: HashMap<u32, u64>, // channel_id -> timestamp
+    #[serde(default)]
+    pub active_connections: HashSet<u32>, // channel_ids that are actively viewing the app
+    #[serde(default)]
+    pub node_profiles: HashMap<String, UserProfile>, // Store profiles of other nodes
+    #[serde(default)]
+    pub file_index: HashMap<String, StoredFile>, // file_id -> stored file metadata
+    #[serde(default)]
+    pub blobs: HashMap<String, StoredBlob>, // content hash -> blob
+    #[serde(default)]
+    pub scheduled_messages: Vec<ScheduledMessage>, // Sorted by send_at
+    #[serde(default)]
+    pub pending_sends: Vec<PendingSend>,
+    #[serde(default)]
+    pub starred_messages: Vec<StarredMessage>,
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    #[serde(default)]
+    pub contacts: HashMap<String, Contact>, // node -> contact
+    #[serde(default)]
+    pub inbound_violations: Vec<ViolationCount>,
+    #[serde(skip)]
+    rate_buckets: HashMap<(String, InboundKind), RateBucket>,
+    #[serde(skip)]
+    guest_rate_buckets: HashMap<(String, InboundKind), RateBucket>, // Keyed by chat key, never reported
+    #[serde(skip)]
+    notification_windows: HashMap<String, NotificationWindow>, // chat_id -> open window
+    #[serde(skip)]
+    public_channels: HashSet<u32>, // Guest channels opened on /public-ws
+    #[serde(default)]
+    pub url_signing_secret: String, // Signs guest file URLs, generated on first start
+    #[serde(default)]
+    pub notification_digest: HashMap<String, u32>, // chat_id -> messages since the last digest
+    #[serde(default)]
+    pub last_digest_at: u64,
+}
+
+fn default_delivery_queue() -> Arc<Mutex<HashMap<String, Vec<ChatMessage>>>> {
+    Arc::new(Mutex::new(HashMap::new()))
 }
 
 impl Default for ChatState {
@@ -416,10 +1386,28 @@ impl Default for ChatState {
             delivery_queue: default_delivery_queue(),
             online_nodes: HashSet::new(),
             ws_connections: HashMap::new(),
-            browser_connections: HashMap::new(),
+            guest_sessions: HashMap::new(),
+            resume_tokens: HashMap::new(),
+            history_changed_at: HashMap::new(),
+            guest_upgrades: HashMap::new(),
             last_heartbeat: HashMap::new(),
             active_connections: HashSet::new(),
             node_profiles: HashMap::new(),
+            file_index: HashMap::new(),
+            blobs: HashMap::new(),
+            scheduled_messages: Vec::new(),
+            pending_sends: Vec::new(),
+            starred_messages: Vec::new(),
+            labels: Vec::new(),
+            contacts: HashMap::new(),
+            inbound_violations: Vec::new(),
+            rate_buckets: HashMap::new(),
+            guest_rate_buckets: HashMap::new(),
+            notification_windows: HashMap::new(),
+            public_channels: HashSet::new(),
+            url_signing_secret: String::new(),
+            notification_digest: HashMap::new(),
+            last_digest_at: 0,
         }
     }
 }
@@ -434,14 +1422,65 @@ impl Default for UserProfile {
 }
 
 const OUR_PROCESS_ID: (&str, &str, &str) = ("chat", "chat", "ware.hypr");
+const PUBLIC_WS_PATH: &str = "/public-ws";
+const GUEST_FILE_URL_TTL_SECS: u64 = 24 * 60 * 60;
+const GUEST_RESUME_TOKEN_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+const WIDGET_AWAY_REPLY_INTERVAL_SECS: u64 = 6 * 60 * 60;
+const WIDGET_TICKET_TTL_SECS: u64 = 12 * 60 * 60;
+const WIDGET_KEY_IDLE_SECS: u64 = 60 * 60; // Widget links nobody wrote on are dropped after this
+const MAX_PENDING_WIDGET_KEYS: usize = 500;
+const MAX_CONNECTED_PENDING_WIDGET_KEYS: usize = 50; // Open tabs that haven't written yet
+const GUEST_UPGRADE_TTL_SECS: u64 = 15 * 60;
+const QR_QUIET_ZONE: u32 = 4; // Modules of blank border scanners expect
+const QR_DEFAULT_SIZE: u32 = 512;
+const QR_MAX_SIZE: u32 = 2048;
+const RETENTION_INTERVAL_MS: u64 = 60 * 60 * 1000; // Enforce retention policies hourly
+const EXPIRY_INTERVAL_MS: u64 = 60 * 1000; // Sweep disappearing messages every minute
+const SCHEDULER_INTERVAL_MS: u64 = 10 * 1000; // Check for due scheduled messages
+const DIGEST_CHECK_INTERVAL_MS: u64 = 60 * 1000; // Check whether a notification digest is due
+const MAX_DISAPPEARING_TIMER_SECS: u64 = 365 * 24 * 60 * 60;
+const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60; // How far ahead of our clock a remote change may be dated
+const MIN_UNDO_SEND_SECONDS: u32 = 5;
+const MAX_UNDO_SEND_SECONDS: u32 = 30;
+const MAX_INBOUND_CONTENT_BYTES: usize = 64 * 1024;
+const MAX_EMOJI_CHARS: usize = 16; // Enough for ZWJ sequences like family emoji
+const MAX_REACTIONS_PER_MESSAGE: usize = 200;
+const MAX_PROFILE_NAME_CHARS: usize = 64;
+const MAX_PROFILE_PIC_BYTES: usize = 512 * 1024;
 const ICON: &str = include_str!("./icon");
 
+// Current Unix time in seconds
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// Send a server message to one WebSocket channel as JSON
+fn push_json<T: Serialize>(channel_id: u32, message: &T) {
+    send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
+        mime: Some("application/json".to_string()),
+        bytes: serde_json::to_string(message).unwrap().into_bytes(),
+    });
+}
+
 // Helper function to enforce one-way status transitions
 fn safe_update_message_status(current: &MessageStatus, new: MessageStatus) -> MessageStatus {
     use MessageStatus::*;
 
     // Define valid transitions
     match (current, &new) {
+        // From Pending, the message either leaves the node or fails
+        (Pending, Sending) | (Pending, Sent) | (Pending, Delivered) | (Pending, Failed) => new,
+
         // From Sending, can go to Sent, Delivered, or Failed
         (Sending, Sent) | (Sending, Delivered) | (Sending, Failed) => new,
 
@@ -475,49 +1514,351 @@ fn compress_data(data: &[u8]) -> Result<Vec<u8>, String> {
     encoder.finish().map_err(|e| format!("Compression finish error: {}", e))
 }
 
-fn decompress_data(compressed: &[u8]) -> Result<Vec<u8>, String> {
-    let mut decoder = GzDecoder::new(compressed);
+// Inflate at most `max_len` bytes, so a small gzip bomb can't exhaust memory
+fn decompress_data(compressed: &[u8], max_len: u64) -> Result<Vec<u8>, String> {
+    let mut decoder = GzDecoder::new(compressed).take(max_len + 1);
     let mut decompressed = Vec::new();
     decoder.read_to_end(&mut decompressed).map_err(|e| format!("Decompression error: {}", e))?;
+    if decompressed.len() as u64 > max_len {
+        return Err(format!("Decompressed data exceeds {} bytes", max_len));
+    }
     Ok(decompressed)
 }
 
-// Helper functions for base64 encoding/decoding (wrapper around base64 0.21)
-fn base64_encode(data: &[u8]) -> String {
-    ::base64::encode(data)
+// Helper functions for serving files
+fn guess_mime_type(filename: &str) -> &'static str {
+    let extension = filename.rsplit('.').next().unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "txt" => "text/plain",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "wav" => "audio/wav",
+        "m4a" => "audio/mp4",
+        "webm" => "video/webm",
+        "mp4" => "video/mp4",
+        "mov" => "video/quicktime",
+        _ => "application/octet-stream",
+    }
 }
 
-fn base64_decode(input: &str) -> Result<Vec<u8>, ::base64::DecodeError> {
-    ::base64::decode(input)
+// Media can be shown inline; everything else is downloaded under its original name.
+// Scriptable types (html, svg) are always downloaded so they can't run on our origin.
+fn content_disposition(filename: &str, mime_type: &str) -> String {
+    let inline = (mime_type.starts_with("image/") && mime_type != "image/svg+xml")
+        || mime_type.starts_with("audio/")
+        || mime_type.starts_with("video/")
+        || mime_type == "application/pdf";
+    let disposition = if inline { "inline" } else { "attachment" };
+
+    // ASCII fallback plus RFC 5987 encoded name for non-ASCII filenames
+    let fallback: String = filename.chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let encoded: String = filename.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect();
+
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", disposition, fallback, encoded)
 }
 
-// Helper function to send push notification for a message
-async fn send_push_notification_for_message(
-    sender: &str,
-    content: &str,
-    chat_id: &str
-) {
-    // Send notification to notifications server (it will send to all registered devices)
-    let notifications_address = Address::new(
-        &our().node,
-        ProcessId::new(Some("notifications"), "distro", "sys")
-    );
+// Parse a single "bytes=" range against a body of `len` bytes.
+// Returns Ok(None) when there is no usable range header, Err(()) when it is unsatisfiable.
+fn parse_byte_range(header: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) => spec.trim(),
+        None => return Ok(None),
+    };
+
+    // Multipart ranges are not supported; serve the whole file instead
+    if spec.contains(',') {
+        return Ok(None);
+    }
 
-    // Truncate message for notification
-    let truncated_content = if content.len() > 100 {
-        format!("{}...", &content[..97])
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // Suffix range: the last N bytes
+        let suffix: u64 = end.parse().map_err(|_| ())?;
+        if suffix == 0 || len == 0 {
+            return Err(());
+        }
+        (len.saturating_sub(suffix), len - 1)
     } else {
-        content.to_string()
+        let start: u64 = start.parse().map_err(|_| ())?;
+        let end: u64 = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            end.parse::<u64>().map_err(|_| ())?.min(len.saturating_sub(1))
+        };
+        if start >= len || start > end {
+            return Err(());
+        }
+        (start, end)
     };
 
-    let notification_action = NotificationsAction::SendNotification {
-        title: format!("Message from {}", sender),
-        body: truncated_content,
-        icon: Some("/icon-180.png".to_string()),
-        data: Some(serde_json::json!({
+    Ok(Some(range))
+}
+
+// Human readable duration for timeline messages, e.g. "1 day" or "30 minutes"
+fn format_duration(seconds: u64) -> String {
+    let (value, unit) = if seconds % (7 * 24 * 60 * 60) == 0 {
+        (seconds / (7 * 24 * 60 * 60), "week")
+    } else if seconds % (24 * 60 * 60) == 0 {
+        (seconds / (24 * 60 * 60), "day")
+    } else if seconds % (60 * 60) == 0 {
+        (seconds / (60 * 60), "hour")
+    } else if seconds % 60 == 0 {
+        (seconds / 60, "minute")
+    } else {
+        (seconds, "second")
+    };
+
+    if value == 1 {
+        format!("1 {}", unit)
+    } else {
+        format!("{} {}s", value, unit)
+    }
+}
+
+// Last timer change wins; if both sides changed it in the same second,
+// the node whose name sorts last wins on both sides
+fn timer_change_wins(current_at: u64, changed_at: u64, changed_by: &str, our_node: &str) -> bool {
+    changed_at > current_at || (changed_at == current_at && changed_by > our_node)
+}
+
+// Helper functions for base64 encoding/decoding (wrapper around base64 0.21)
+fn base64_encode(data: &[u8]) -> String {
+    ::base64::encode(data)
+}
+
+fn base64_decode(input: &str) -> Result<Vec<u8>, ::base64::DecodeError> {
+    ::base64::decode(input)
+}
+
+// Modules are drawn as square cells of `scale` pixels inside a QR_QUIET_ZONE-module border
+struct QrLayout {
+    modules: Vec<bool>, // Row-major, true = dark
+    width: u32,         // Modules per side, without the quiet zone
+    scale: u32,
+    icon: Option<(u32, u32)>, // First module and side length of the square cleared for the icon
+}
+
+impl QrLayout {
+    fn new(data: &str, level: &QrErrorCorrection, size: u32, with_icon: bool) -> Result<Self, String> {
+        let ec_level = match level {
+            QrErrorCorrection::Low => qrcode::EcLevel::L,
+            QrErrorCorrection::Medium => qrcode::EcLevel::M,
+            QrErrorCorrection::Quartile => qrcode::EcLevel::Q,
+            QrErrorCorrection::High => qrcode::EcLevel::H,
+        };
+        let code = qrcode::QrCode::with_error_correction_level(data.as_bytes(), ec_level)
+            .map_err(|e| format!("Can't encode QR code: {:?}", e))?;
+
+        let width = code.width() as u32;
+        let modules = code.to_colors().into_iter()
+            .map(|color| color == qrcode::Color::Dark)
+            .collect();
+        let total = width + 2 * QR_QUIET_ZONE;
+        let scale = (size / total).max(1);
+
+        // The icon covers about a fifth of the symbol, which Quartile and High recover from
+        let icon = with_icon.then(|| {
+            let side = (width / 5) | 1;
+            ((width - side) / 2, side)
+        });
+
+        Ok(QrLayout { modules, width, scale, icon })
+    }
+
+    fn pixel_size(&self) -> u32 {
+        (self.width + 2 * QR_QUIET_ZONE) * self.scale
+    }
+
+    fn is_dark(&self, x: u32, y: u32) -> bool {
+        if let Some((start, side)) = self.icon {
+            if (start..start + side).contains(&x) && (start..start + side).contains(&y) {
+                return false;
+            }
+        }
+        self.modules[(y * self.width + x) as usize]
+    }
+
+    // Pixel rectangle the icon is drawn in, half a module inside its cleared square
+    fn icon_rect(&self) -> Option<(u32, u32)> {
+        self.icon.map(|(start, side)| {
+            let inset = (self.scale / 2).max(1);
+            ((start + QR_QUIET_ZONE) * self.scale + inset, (side * self.scale).saturating_sub(2 * inset))
+        })
+    }
+
+    fn to_svg(&self) -> String {
+        let total = self.width + 2 * QR_QUIET_ZONE;
+        let size = self.pixel_size();
+        let mut path = String::new();
+        for y in 0..self.width {
+            for x in 0..self.width {
+                if self.is_dark(x, y) {
+                    path.push_str(&format!("M{} {}h1v1h-1z", x + QR_QUIET_ZONE, y + QR_QUIET_ZONE));
+                }
+            }
+        }
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{size}\" height=\"{size}\" viewBox=\"0 0 {total} {total}\" shape-rendering=\"crispEdges\">\
+             <rect width=\"{total}\" height=\"{total}\" fill=\"#fff\"/><path d=\"{path}\" fill=\"#000\"/>",
+        );
+        if let Some((offset, side)) = self.icon_rect() {
+            // viewBox units are modules
+            let (offset, side) = (offset as f32 / self.scale as f32, side as f32 / self.scale as f32);
+            svg.push_str(&format!(
+                "<image href=\"{}\" x=\"{offset}\" y=\"{offset}\" width=\"{side}\" height=\"{side}\"/>",
+                ICON.trim(),
+            ));
+        }
+        svg.push_str("</svg>");
+        svg
+    }
+
+    fn to_png(&self) -> Result<Vec<u8>, String> {
+        let size = self.pixel_size();
+        let mut pixels = vec![255u8; (size * size * 3) as usize];
+        for y in 0..self.width {
+            for x in 0..self.width {
+                if !self.is_dark(x, y) {
+                    continue;
+                }
+                for py in (y + QR_QUIET_ZONE) * self.scale..(y + QR_QUIET_ZONE + 1) * self.scale {
+                    let row = (py * size) as usize;
+                    let from = (row + ((x + QR_QUIET_ZONE) * self.scale) as usize) * 3;
+                    pixels[from..from + (self.scale * 3) as usize].fill(0);
+                }
+            }
+        }
+
+        if let Some((offset, side)) = self.icon_rect() {
+            let (icon_width, icon_height, icon) = decode_icon()?;
+            // Nearest-neighbour scaling, blended over the white background
+            for py in 0..side {
+                for px in 0..side {
+                    let [r, g, b, a] = icon[((py * icon_height / side) * icon_width + px * icon_width / side) as usize];
+                    let blend = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+                    let at = (((offset + py) * size + offset + px) * 3) as usize;
+                    pixels[at..at + 3].copy_from_slice(&[blend(r), blend(g), blend(b)]);
+                }
+            }
+        }
+
+        let mut png_data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png_data, size, size);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()
+            .map_err(|e| format!("Failed to encode PNG: {}", e))?;
+        writer.write_image_data(&pixels)
+            .map_err(|e| format!("Failed to encode PNG: {}", e))?;
+        writer.finish()
+            .map_err(|e| format!("Failed to encode PNG: {}", e))?;
+        Ok(png_data)
+    }
+}
+
+// The app icon as RGBA pixels, for drawing into PNG QR codes
+fn decode_icon() -> Result<(u32, u32, Vec<[u8; 4]>), String> {
+    let encoded = ICON.trim().splitn(2, ',').nth(1).unwrap_or_default();
+    let bytes = base64_decode(encoded)
+        .map_err(|e| format!("Failed to decode icon: {}", e))?;
+
+    let mut decoder = png::Decoder::new(bytes.as_slice());
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()
+        .map_err(|e| format!("Failed to decode icon: {}", e))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)
+        .map_err(|e| format!("Failed to decode icon: {}", e))?;
+
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => buf[..info.buffer_size()].chunks_exact(4).map(|p| [p[0], p[1], p[2], p[3]]).collect(),
+        png::ColorType::Rgb => buf[..info.buffer_size()].chunks_exact(3).map(|p| [p[0], p[1], p[2], 255]).collect(),
+        png::ColorType::GrayscaleAlpha => buf[..info.buffer_size()].chunks_exact(2).map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => buf[..info.buffer_size()].iter().map(|&v| [v, v, v, 255]).collect(),
+        png::ColorType::Indexed => return Err("Unexpected indexed icon after expansion".to_string()),
+    };
+    Ok((info.width, info.height, pixels))
+}
+
+fn normalize_nickname(nickname: Option<String>) -> Option<String> {
+    nickname
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+}
+
+// Cut at a character boundary so multi-byte text can't panic
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    if text.chars().count() > max_chars {
+        let cut: String = text.chars().take(max_chars.saturating_sub(3)).collect();
+        format!("{}...", cut)
+    } else {
+        text.to_string()
+    }
+}
+
+// Notification text for a message, based on what kind of message it is
+fn notification_body(message: &ChatMessage, hide_preview: bool) -> String {
+    if hide_preview {
+        return "New message".to_string();
+    }
+    match message.message_type {
+        MessageType::Text | MessageType::System => truncate_chars(&message.content, 100),
+        MessageType::Image => "📷 Photo".to_string(),
+        MessageType::VoiceNote => "🎤 Voice note".to_string(),
+        MessageType::File => match message.file_info {
+            Some(ref file_info) => format!("📎 {}", truncate_chars(&file_info.filename, 80)),
+            None => "📎 File".to_string(),
+        },
+    }
+}
+
+// Helper function to send push notification for a message
+async fn send_push_notification_for_message(
+    sender: &str,
+    title: &str,
+    body: &str,
+    chat_id: &str
+) {
+    // Send notification to notifications server (it will send to all registered devices)
+    let notifications_address = Address::new(
+        &our().node,
+        ProcessId::new(Some("notifications"), "distro", "sys")
+    );
+
+    let notification_action = NotificationsAction::SendNotification {
+        title: title.to_string(),
+        body: body.to_string(),
+        icon: Some("/icon-180.png".to_string()),
+        data: Some(serde_json::json!({
             "url": format!("/chat#{}", chat_id),
             "chat_id": chat_id,
             "sender": sender,
+            "tag": chat_id, // Later pushes for the chat replace this one
             "appId": "chat:chat:ware.hypr",
             "appLabel": "Chat"
         })),
@@ -599,6 +1940,10 @@ async fn send_push_notification_for_message(
             path: "/ws",
             config: WsBindingConfig::default(),
         },
+        Binding::Ws {
+            path: "/public-ws",
+            config: WsBindingConfig::new(false, false, false),
+        },
         Binding::Http {
             path: "/public",
             config: HttpBindingConfig::new(false, false, false, None)
@@ -622,6 +1967,19 @@ impl ChatState {
             self.profile.name = our_node.split('.').next().unwrap_or("User").to_string();
         }
 
+        if self.url_signing_secret.is_empty() {
+            self.url_signing_secret = format!("{:x}{:x}", rand::random::<u128>(), rand::random::<u128>());
+        }
+
+        // Guest chats saved before is_guest existed are recognised by their chat links
+        let guest_chat_ids: HashSet<String> = self.chat_keys.values()
+            .filter(|key| key.upgraded_to.is_none())
+            .map(|key| key.chat_id.clone())
+            .collect();
+        for chat in self.chats.values_mut().filter(|chat| guest_chat_ids.contains(&chat.id)) {
+            chat.is_guest = true;
+        }
+
         // Create VFS drive for storing chat files
         let package_id = our().package_id();
         match vfs::create_drive(package_id, "files", Some(5)) {
@@ -635,14 +1993,9 @@ impl ChatState {
 
         // Add a welcome chat if no chats exist
         if self.chats.is_empty() {
-            let timestamp = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
+            let timestamp = now_secs();
 
             let welcome_chat = Chat {
-                id: "system:welcome".to_string(),
-                counterparty: "System".to_string(),
                 messages: vec![ChatMessage {
                     id: format!("welcome_{}", timestamp),
                     sender: "System".to_string(),
@@ -653,12 +2006,10 @@ impl ChatState {
                     reactions: Vec::new(),
                     message_type: MessageType::Text,
                     file_info: None,
+                    expires_at: None,
                 }],
-                last_activity: timestamp,
-                unread_count: 0,
-                is_blocked: false,
                 notify: false,
-                counterparty_profile: None,
+                ..Chat::new("system:welcome".to_string(), "System".to_string(), timestamp)
             };
 
             self.chats.insert("system:welcome".to_string(), welcome_chat);
@@ -710,6 +2061,72 @@ impl ChatState {
             }
         });
 
+        // Spawn a task to periodically apply retention policies.
+        // State can't be touched from here, so go through our own local endpoint.
+        spawn(async move {
+            let our_address = Address::from((our().node.as_str(), OUR_PROCESS_ID));
+            loop {
+                let _ = sleep(RETENTION_INTERVAL_MS).await;
+
+                match enforce_retention_local_rpc(&our_address).await {
+                    Ok(Ok(removed)) if removed > 0 => println!("Retention: cleaned up {} messages", removed),
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => println!("Retention enforcement failed: {}", e),
+                    Err(e) => println!("Failed to run retention enforcement: {:?}", e),
+                }
+            }
+        });
+
+        // Spawn a task to periodically delete expired disappearing messages
+        spawn(async move {
+            let our_address = Address::from((our().node.as_str(), OUR_PROCESS_ID));
+            loop {
+                let _ = sleep(EXPIRY_INTERVAL_MS).await;
+
+                match expire_messages_local_rpc(&our_address).await {
+                    Ok(Ok(removed)) if removed > 0 => println!("Expired {} disappearing messages", removed),
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => println!("Message expiry failed: {}", e),
+                    Err(e) => println!("Failed to run message expiry: {:?}", e),
+                }
+            }
+        });
+
+        // Spawn a task to release scheduled messages when they are due.
+        // Anything that came due while we were offline goes out on the first tick.
+        spawn(async move {
+            let our_address = Address::from((our().node.as_str(), OUR_PROCESS_ID));
+            loop {
+                let _ = sleep(SCHEDULER_INTERVAL_MS).await;
+
+                match release_scheduled_messages_local_rpc(&our_address).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => println!("Releasing scheduled messages failed: {}", e),
+                    Err(e) => println!("Failed to release scheduled messages: {:?}", e),
+                }
+            }
+        });
+
+        // Spawn a task to send notification digests for muted and low-priority chats
+        spawn(async move {
+            let our_address = Address::from((our().node.as_str(), OUR_PROCESS_ID));
+            loop {
+                let _ = sleep(DIGEST_CHECK_INTERVAL_MS).await;
+
+                match send_notification_digest_local_rpc(&our_address).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => println!("Sending notification digest failed: {}", e),
+                    Err(e) => println!("Failed to send notification digest: {:?}", e),
+                }
+            }
+        });
+
+        // Resume undo-send timers for messages that were still pending when we stopped
+        let now = now_secs();
+        for pending in &self.pending_sends {
+            Self::spawn_pending_release(&pending.chat_id, &pending.message.id, pending.release_at.saturating_sub(now));
+        }
+
         println!("Chat app initialized on node: {} with {} chats", our().node, self.chats.len());
     }
 
@@ -718,26 +2135,22 @@ impl ChatState {
     #[local]
     #[http]
     async fn create_chat(&mut self, req: CreateChatReq) -> Result<Chat, String> {
+        // Accept a contact's nickname in place of their node name
+        let req = CreateChatReq {
+            counterparty: self.resolve_counterparty(&req.counterparty)?,
+        };
 
         // Normalize chat ID to always be alphabetically sorted
         let chat_id = Self::normalize_chat_id(&our().node, &req.counterparty);
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let timestamp = now_secs();
 
         // Get counterparty profile if we have it
         let counterparty_profile = self.node_profiles.get(&req.counterparty).cloned();
 
         let chat = Chat {
-            id: chat_id.clone(),
-            counterparty: req.counterparty.clone(),
-            messages: Vec::new(),
-            last_activity: timestamp,
-            unread_count: 0,
-            is_blocked: false,
-            notify: true,
             counterparty_profile,
+            counterparty_nickname: self.contacts.get(&req.counterparty).and_then(|c| c.nickname.clone()),
+            ..Chat::new(chat_id.clone(), req.counterparty.clone(), timestamp)
         };
 
         self.chats.insert(chat_id, chat.clone());
@@ -768,17 +2181,85 @@ impl ChatState {
 
     #[local]
     #[http]
-    async fn get_chats(&self) -> Result<Vec<Chat>, String> {
-        let mut chats: Vec<Chat> = self.chats.values().cloned().collect();
+    async fn get_chats(&self, req: GetChatsReq) -> Result<Vec<Chat>, String> {
+        let chats = self.list_chats(&req);
         println!("get_chats: Returning {} chats", chats.len());
         for chat in &chats {
             println!("  Chat: {} with {}", chat.id, chat.counterparty);
         }
-        chats.sort_by(|a, b| b.last_activity.cmp(&a.last_activity));
 
         Ok(chats)
     }
 
+    #[local]
+    #[http]
+    async fn get_archived_chats(&self) -> Result<Vec<Chat>, String> {
+        Ok(self.list_chats(&GetChatsReq {
+            archived: Some(true),
+            ..Default::default()
+        }))
+    }
+
+    // LABELS
+
+    #[http]
+    async fn get_labels(&self) -> Result<Vec<Label>, String> {
+        Ok(self.labels.clone())
+    }
+
+    #[http]
+    async fn create_label(&mut self, req: CreateLabelReq) -> Result<Label, String> {
+        self.add_label(req)
+    }
+
+    #[http]
+    async fn update_label(&mut self, req: UpdateLabelReq) -> Result<Label, String> {
+        self.edit_label(req)
+    }
+
+    #[http]
+    async fn delete_label(&mut self, req: DeleteLabelReq) -> Result<String, String> {
+        self.remove_label(&req.label_id)?;
+        Ok("Label deleted".to_string())
+    }
+
+    #[http]
+    async fn set_chat_labels(&mut self, req: SetChatLabelsReq) -> Result<Chat, String> {
+        self.apply_chat_labels(req)
+    }
+
+    #[http]
+    async fn archive_chat(&mut self, req: ArchiveChatReq) -> Result<Chat, String> {
+        self.update_chat(&req.chat_id, |chat| chat.is_archived = true)
+    }
+
+    #[http]
+    async fn unarchive_chat(&mut self, req: ArchiveChatReq) -> Result<Chat, String> {
+        self.update_chat(&req.chat_id, |chat| chat.is_archived = false)
+    }
+
+    #[http]
+    async fn mute_chat(&mut self, req: MuteChatReq) -> Result<Chat, String> {
+        self.update_chat(&req.chat_id, |chat| match req.until {
+            Some(until) => {
+                chat.notify = true;
+                chat.muted_until = Some(until);
+            }
+            None => {
+                chat.notify = false;
+                chat.muted_until = None;
+            }
+        })
+    }
+
+    #[http]
+    async fn unmute_chat(&mut self, req: UnmuteChatReq) -> Result<Chat, String> {
+        self.update_chat(&req.chat_id, |chat| {
+            chat.notify = true;
+            chat.muted_until = None;
+        })
+    }
+
     #[local]
     #[http]
     async fn get_chat(&self, req: GetChatReq) -> Result<Chat, String> {
@@ -898,144 +2379,222 @@ impl ChatState {
     async fn delete_chat(&mut self, req: DeleteChatReq) -> Result<String, String> {
 
         self.chats.remove(&req.chat_id)
-            .ok_or_else(|| "Chat not found".to_string())
-            .map(|_| "Chat deleted".to_string())
+            .ok_or_else(|| "Chat not found".to_string())?;
+
+        self.clear_chat_data(&req.chat_id);
+
+        Ok("Chat deleted".to_string())
     }
 
-    // MESSAGE OPERATIONS
+    // MESSAGE REQUESTS
 
-    #[local]
     #[http]
-    async fn send_message(&mut self, req: SendMessageReq) -> Result<ChatMessage, String> {
+    async fn get_message_requests(&self) -> Result<Vec<Chat>, String> {
+        Ok(self.list_chats(&GetChatsReq {
+            requests: Some(true),
+            ..Default::default()
+        }))
+    }
 
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    #[http]
+    async fn accept_message_request(&mut self, req: MessageRequestReq) -> Result<Chat, String> {
+        let chat = self.chats.get(&req.chat_id)
+            .ok_or_else(|| "Chat not found".to_string())?;
+        if !chat.is_request {
+            return Err("Chat is not a message request".to_string());
+        }
 
-        let message_id = format!("{}:{}", timestamp, rand::random::<u32>());
+        self.update_chat(&req.chat_id, |chat| chat.is_request = false)
+    }
 
-        let message = ChatMessage {
-            id: message_id,
-            sender: our().node.clone(),
-            content: req.content,
-            timestamp,
-            status: MessageStatus::Sending,
-            reply_to: req.reply_to,
-            reactions: Vec::new(),
-            message_type: MessageType::Text,
-            file_info: None,
-        };
+    #[http]
+    async fn decline_message_request(&mut self, req: MessageRequestReq) -> Result<String, String> {
+        match self.chats.get(&req.chat_id) {
+            Some(chat) if chat.is_request => {}
+            Some(_) => return Err("Chat is not a message request".to_string()),
+            None => return Err("Chat not found".to_string()),
+        }
 
-        // Add to chat if it exists, or create new chat
-        let chat = self.chats.entry(req.chat_id.clone()).or_insert_with(|| {
-            let counterparty = req.chat_id.split(':').nth(1).unwrap_or("unknown").to_string();
-            Chat {
-                id: req.chat_id.clone(),
-                counterparty,
-                messages: Vec::new(),
-                last_activity: timestamp,
-                unread_count: 0,
-                is_blocked: false,
-                notify: true,
-                counterparty_profile: None,
-            }
-        });
+        self.chats.remove(&req.chat_id);
+        self.clear_chat_data(&req.chat_id);
 
-        chat.messages.push(message.clone());
-        chat.last_activity = timestamp;
+        Ok("Message request declined".to_string())
+    }
 
-        // Immediately update status to Sent (backend has received the message)
-        if let Some(msg) = chat.messages.iter_mut().find(|m| m.id == message.id) {
-            msg.status = safe_update_message_status(&msg.status, MessageStatus::Sent);
+    #[http]
+    async fn decline_and_block_message_request(&mut self, req: MessageRequestReq) -> Result<Chat, String> {
+        match self.chats.get(&req.chat_id) {
+            Some(chat) if chat.is_request => {}
+            Some(_) => return Err("Chat is not a message request".to_string()),
+            None => return Err("Chat not found".to_string()),
         }
 
-        // Send ChatUpdate immediately to show Sent status
-        for &channel_id in self.ws_connections.keys() {
-            let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-            send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                mime: Some("application/json".to_string()),
-                bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-            });
-        }
+        // Keep an empty, blocked chat around so further messages from the node are dropped
+        self.clear_chat_data(&req.chat_id);
+        self.update_chat(&req.chat_id, |chat| {
+            chat.messages.clear();
+            chat.pinned_messages.clear();
+            chat.unread_count = 0;
+            chat.is_request = false;
+            chat.is_blocked = true;
+            chat.is_archived = true;
+        })
+    }
 
-        // Send to counterparty via P2P using generated RPC
-        let counterparty = chat.counterparty.clone();
-        let msg_to_send = message.clone();
-        let message_id_clone = message.id.clone();
-        let delivery_queue = self.delivery_queue.clone();
+    #[http]
+    async fn get_storage_usage(&self) -> Result<StorageUsage, String> {
+        Ok(self.compute_storage_usage())
+    }
 
-        let target = Address::from((counterparty.as_str(), OUR_PROCESS_ID));
+    // RETENTION
 
-        // Spawn task to attempt delivery without blocking
-        spawn(async move {
-            // Try to send using generated RPC method and queue if it fails
-            let msg_json = serde_json::to_value(&msg_to_send).unwrap();
-            let msg_for_rpc: CUChatMessage = serde_json::from_value(msg_json).unwrap();
-            match receive_message_remote_rpc(&target, msg_for_rpc).await {
-                Ok(_) => {
-                    println!("Message {} sent successfully to {}", message_id_clone, counterparty);
-                    // Message delivered successfully, counterparty will send ACK
-                }
-                Err(_) => {
-                    println!("Failed to send message {} to {}, adding to delivery queue", message_id_clone, counterparty);
-                    // Failed to send immediately, add to delivery queue
-                    let mut queue = delivery_queue.lock().unwrap();
-                    queue.entry(counterparty.clone())
-                        .or_insert_with(Vec::new)
-                        .push(msg_to_send);
-                }
-            }
-        });
+    #[http]
+    async fn set_chat_retention(&mut self, req: SetChatRetentionReq) -> Result<Chat, String> {
+        let chat = self.chats.get_mut(&req.chat_id)
+            .ok_or_else(|| "Chat not found".to_string())?;
+        chat.retention = req.retention;
+        let chat = chat.clone();
 
-        // Return the message with updated status
-        if let Some(chat) = self.chats.get(&req.chat_id) {
-            if let Some(updated_msg) = chat.messages.iter().find(|m| m.id == message.id) {
-                return Ok(updated_msg.clone());
-            }
-        }
+        // Apply the new policy right away rather than waiting for the next run
+        self.apply_retention();
 
-        Ok(message)
+        Ok(self.chats.get(&req.chat_id).cloned().unwrap_or(chat))
     }
 
+    #[local]
     #[http]
-    async fn edit_message(&mut self, req: EditMessageReq) -> Result<String, String> {
+    async fn enforce_retention(&mut self) -> Result<u32, String> {
+        Ok(self.apply_retention())
+    }
 
-        // Find message in the specified chat
-        if let Some(chat) = self.chats.get_mut(&req.chat_id) {
-            if let Some(message) = chat.messages.iter_mut().find(|m| m.id == req.message_id) {
-                message.content = req.new_content;
-                return Ok("Message edited".to_string());
-            }
+    // DISAPPEARING MESSAGES
+
+    #[http]
+    async fn set_disappearing_timer(&mut self, req: SetDisappearingTimerReq) -> Result<Chat, String> {
+        if req.seconds == Some(0) {
+            return Err("Timer must be at least one second".to_string());
+        }
+        if req.seconds.is_some_and(|seconds| seconds > MAX_DISAPPEARING_TIMER_SECS) {
+            return Err(format!("Timer can be at most {}", format_duration(MAX_DISAPPEARING_TIMER_SECS)));
         }
 
-        Err("Message not found".to_string())
-    }
+        let timestamp = now_secs();
 
-    #[http]
-    async fn delete_message(&mut self, req: DeleteMessageReq) -> Result<String, String> {
+        let counterparty = self.chats.get(&req.chat_id)
+            .ok_or_else(|| "Chat not found".to_string())?
+            .counterparty
+            .clone();
 
-        // Find and remove message from the specified chat
-        if let Some(chat) = self.chats.get_mut(&req.chat_id) {
-            if let Some(pos) = chat.messages.iter().position(|m| m.id == req.message_id) {
-                // Store counterparty before removing message
-                let counterparty = chat.counterparty.clone();
-                let message_id = req.message_id.clone();
-                let chat_id = req.chat_id.clone();
+        let our_node = our().node.clone();
+        self.update_disappearing_timer(&req.chat_id, req.seconds, timestamp, &our_node);
+
+        // Tell the counterparty so both sides apply the same timer
+        if !req.chat_id.starts_with("browser:") {
+            let target = Address::from((counterparty.as_str(), OUR_PROCESS_ID));
+            let seconds = req.seconds;
+            spawn(async move {
+                match receive_disappearing_timer_remote_rpc(&target, our_node, seconds, timestamp).await {
+                    Ok(_) => println!("Shared disappearing timer with {}", counterparty),
+                    Err(e) => println!("Failed to share disappearing timer with {}: {:?}", counterparty, e),
+                }
+            });
+        }
+
+        self.chats.get(&req.chat_id)
+            .cloned()
+            .ok_or_else(|| "Chat not found".to_string())
+    }
+
+    #[local]
+    #[http]
+    async fn expire_messages(&mut self) -> Result<u32, String> {
+        self.clear_expired_mutes();
+        self.remove_idle_widget_keys();
+        Ok(self.remove_expired_messages())
+    }
+
+    // MESSAGE OPERATIONS
+
+    #[local]
+    #[http]
+    async fn send_message(&mut self, req: SendMessageReq) -> Result<ChatMessage, String> {
+        let timestamp = now_secs();
+
+        let message_id = format!("{}:{}", timestamp, rand::random::<u32>());
+
+        // Messages due in the future wait in the scheduler
+        if let Some(send_at) = req.send_at.filter(|&send_at| send_at > timestamp) {
+            let scheduled = ScheduledMessage {
+                id: message_id,
+                chat_id: req.chat_id,
+                send_at,
+                created_at: timestamp,
+                message_type: MessageType::Text,
+                content: req.content,
+                reply_to: req.reply_to,
+                filename: None,
+                mime_type: None,
+                data: None,
+                duration: None,
+                file_id: None,
+            };
+            return Ok(self.schedule_message(scheduled));
+        }
+
+        self.send_message_now(req, message_id)
+    }
+
+    #[http]
+    async fn edit_message(&mut self, req: EditMessageReq) -> Result<String, String> {
+
+        // Find message in the specified chat
+        let chat = self.chats.get_mut(&req.chat_id)
+            .ok_or_else(|| "Message not found".to_string())?;
+        let message = chat.messages.iter_mut()
+            .find(|m| m.id == req.message_id)
+            .ok_or_else(|| "Message not found".to_string())?;
+        message.content = req.new_content.clone();
+
+        // A message still in its undo-send grace period goes out with the edited content,
+        // so the held copy has to change along with the one shown in the chat
+        if let Some(pending) = self.pending_sends.iter_mut()
+            .find(|p| p.chat_id == req.chat_id && p.message.id == req.message_id)
+        {
+            pending.message.content = req.new_content;
+
+            let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+            self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+        }
+
+        self.mark_history_changed(&req.chat_id);
+        Ok("Message edited".to_string())
+    }
+
+    #[http]
+    async fn delete_message(&mut self, req: DeleteMessageReq) -> Result<String, String> {
+
+        // Find and remove message from the specified chat
+        if let Some(chat) = self.chats.get_mut(&req.chat_id) {
+            if let Some(pos) = chat.messages.iter().position(|m| m.id == req.message_id) {
+                // Store counterparty before removing message
+                let counterparty = chat.counterparty.clone();
+                let message_id = req.message_id.clone();
+                let chat_id = req.chat_id.clone();
                 let delete_for_both = req.delete_for_both.unwrap_or(false);
 
                 // Remove the message
                 chat.messages.remove(pos);
+                chat.pinned_messages.retain(|p| p.message_id != message_id);
 
                 // Notify all WebSocket connections about the updated chat
                 for &channel_id in self.ws_connections.keys() {
                     let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                    send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                    });
+                    push_json(channel_id, &chat_update);
                 }
 
+                // Free any stored file no other message references and drop its star
+                self.forget_message(&chat_id, &message_id);
+
                 // Only send deletion notification to counterparty if deleting for both
                 if delete_for_both {
                     let target = Address::from((counterparty.as_str(), OUR_PROCESS_ID));
@@ -1054,10 +2613,7 @@ impl ChatState {
     #[http]
     async fn add_reaction(&mut self, req: AddReactionReq) -> Result<String, String> {
 
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let timestamp = now_secs();
 
         let reaction = MessageReaction {
             emoji: req.emoji.clone(),
@@ -1097,12 +2653,10 @@ impl ChatState {
                     // Notify WebSocket connections
                     for &channel_id in self.ws_connections.keys() {
                         let msg = WsServerMessage::ChatUpdate(chat.clone());
-                        send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                            mime: Some("application/json".to_string()),
-                            bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-                        });
+                        push_json(channel_id, &msg);
                     }
 
+                    self.mark_history_changed(&req.chat_id);
                     return Ok("Reaction added".to_string());
                 } else {
                     return Ok("Already reacted".to_string());
@@ -1123,12 +2677,9 @@ impl ChatState {
 
         let original_message = message_to_forward.ok_or_else(|| "Message not found".to_string())?;
 
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let timestamp = now_secs();
 
-        let forwarded_message = ChatMessage {
+        let mut forwarded_message = ChatMessage {
             id: format!("{}:{}", timestamp, rand::random::<u32>()),
             sender: our().node.clone(),
             content: format!("Forwarded: {}", original_message.content),
@@ -1138,20 +2689,26 @@ impl ChatState {
             reactions: Vec::new(),
             message_type: original_message.message_type.clone(),
             file_info: original_message.file_info.clone(),
+            expires_at: self.message_expiry(&req.to_chat_id, timestamp),
         };
 
+        // Reference the same stored content from the destination chat instead of copying it
+        if let Some(source) = self.stored_file_for_message(&req.from_chat_id, &original_message.id).cloned() {
+            let linked = self.link_stored_file(&source, &req.to_chat_id, &forwarded_message.id, timestamp);
+            if let Some(ref mut file_info) = forwarded_message.file_info {
+                if file_info.url.starts_with("/files/") {
+                    file_info.url = Self::file_url(&linked);
+                }
+            }
+        }
+
         // Add to destination chat
         let chat = self.chats.entry(req.to_chat_id.clone()).or_insert_with(|| {
             let counterparty = req.to_chat_id.split(':').nth(1).unwrap_or("unknown").to_string();
             Chat {
-                id: req.to_chat_id.clone(),
-                counterparty: counterparty.clone(),
-                messages: Vec::new(),
-                last_activity: timestamp,
-                unread_count: 0,
-                is_blocked: false,
-                notify: true,
                 counterparty_profile: self.node_profiles.get(&counterparty).cloned(),
+                counterparty_nickname: self.contacts.get(&counterparty).and_then(|c| c.nickname.clone()),
+                ..Chat::new(req.to_chat_id.clone(), counterparty.clone(), timestamp)
             }
         });
 
@@ -1161,7 +2718,12 @@ impl ChatState {
         // Send to counterparty if it's a node-to-node chat
         if !req.to_chat_id.starts_with("browser:") {
             let counterparty = chat.counterparty.clone();
-            let msg_to_send = forwarded_message.clone();
+            let mut msg_to_send = forwarded_message.clone();
+
+            // The local /files/ URL means nothing to the counterparty, so send the content itself
+            if let Err(e) = self.prepare_outgoing_file(&mut msg_to_send, &req.to_chat_id) {
+                println!("Failed to attach forwarded file: {}", e);
+            }
 
             let target = Address::from((counterparty.as_str(), OUR_PROCESS_ID));
 
@@ -1178,10 +2740,7 @@ impl ChatState {
                         // Send ChatUpdate with the updated message status
                         for &channel_id in self.ws_connections.keys() {
                             let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                            send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                                mime: Some("application/json".to_string()),
-                                bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                            });
+                            push_json(channel_id, &chat_update);
                         }
                     }
                 }
@@ -1219,12 +2778,10 @@ impl ChatState {
                     // Notify WebSocket connections
                     for &channel_id in self.ws_connections.keys() {
                         let msg = WsServerMessage::ChatUpdate(chat.clone());
-                        send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                            mime: Some("application/json".to_string()),
-                            bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-                        });
+                        push_json(channel_id, &msg);
                     }
 
+                    self.mark_history_changed(&req.chat_id);
                     return Ok("Reaction removed".to_string());
                 }
             }
@@ -1233,31 +2790,203 @@ impl ChatState {
         Err("Reaction not found".to_string())
     }
 
+    // PINNED MESSAGES
+
+    #[http]
+    async fn pin_message(&mut self, req: PinMessageReq) -> Result<Chat, String> {
+        self.set_message_pinned(req.chat_id, req.message_id, true)
+    }
+
+    #[http]
+    async fn unpin_message(&mut self, req: PinMessageReq) -> Result<Chat, String> {
+        self.set_message_pinned(req.chat_id, req.message_id, false)
+    }
+
+    #[http]
+    async fn get_pinned(&self, req: GetPinnedReq) -> Result<Vec<ChatMessage>, String> {
+        let chat = self.chats.get(&req.chat_id)
+            .ok_or_else(|| "Chat not found".to_string())?;
+
+        // Most recently pinned first, independent of which page of history is loaded
+        let mut pins = chat.pinned_messages.clone();
+        pins.sort_by(|a, b| b.pinned_at.cmp(&a.pinned_at));
+
+        Ok(pins.iter()
+            .filter_map(|pin| chat.messages.iter().find(|m| m.id == pin.message_id))
+            .cloned()
+            .collect())
+    }
+
+    // STARRED MESSAGES
+
+    #[http]
+    async fn star_message(&mut self, req: StarMessageReq) -> Result<String, String> {
+        let exists = self.chats.get(&req.chat_id)
+            .map_or(false, |chat| chat.messages.iter().any(|m| m.id == req.message_id));
+        if !exists {
+            return Err("Message not found".to_string());
+        }
+
+        if self.is_starred(&req.chat_id, &req.message_id) {
+            return Ok("Already starred".to_string());
+        }
+
+        let timestamp = now_secs();
+
+        self.starred_messages.push(StarredMessage {
+            chat_id: req.chat_id,
+            message_id: req.message_id,
+            starred_at: timestamp,
+        });
+        self.broadcast_starred_update();
+
+        Ok("Message starred".to_string())
+    }
+
+    #[http]
+    async fn unstar_message(&mut self, req: StarMessageReq) -> Result<String, String> {
+        if !self.is_starred(&req.chat_id, &req.message_id) {
+            return Err("Message not starred".to_string());
+        }
+
+        self.unstar(&req.chat_id, &req.message_id);
+        Ok("Message unstarred".to_string())
+    }
+
+    #[http]
+    async fn get_starred(&self, req: GetStarredReq) -> Result<Vec<StarredMessageEntry>, String> {
+        let mut entries: Vec<StarredMessageEntry> = self.starred_messages.iter()
+            .filter(|s| match (req.before_starred_at, req.before_message_id.as_ref()) {
+                (Some(before), Some(before_id)) => (s.starred_at, &s.message_id) < (before, before_id),
+                (Some(before), None) => s.starred_at < before,
+                (None, _) => true,
+            })
+            .filter_map(|s| {
+                let chat = self.chats.get(&s.chat_id)?;
+                let message = chat.messages.iter().find(|m| m.id == s.message_id)?;
+                Some(StarredMessageEntry {
+                    chat_id: s.chat_id.clone(),
+                    counterparty: chat.counterparty.clone(),
+                    starred_at: s.starred_at,
+                    message: message.clone(),
+                })
+            })
+            .collect();
+
+        // Newest first; page with before_starred_at and before_message_id set from the last entry
+        entries.sort_by(|a, b| (b.starred_at, &b.message.id).cmp(&(a.starred_at, &a.message.id)));
+        entries.truncate(req.limit.unwrap_or(50) as usize);
+
+        Ok(entries)
+    }
+
+    #[http]
+    async fn get_message_context(&self, req: GetMessageContextReq) -> Result<MessageContext, String> {
+        let chat = self.chats.get(&req.chat_id)
+            .ok_or_else(|| "Chat not found".to_string())?;
+
+        let mut messages = chat.messages.clone();
+        messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+        let pos = messages.iter()
+            .position(|m| m.id == req.message_id)
+            .ok_or_else(|| "Message not found".to_string())?;
+
+        let radius = req.radius.unwrap_or(20) as usize;
+        let start = pos.saturating_sub(radius);
+        let end = (pos + radius + 1).min(messages.len());
+
+        Ok(MessageContext {
+            chat_id: req.chat_id,
+            has_older: start > 0,
+            has_newer: end < messages.len(),
+            target_index: (pos - start) as u32,
+            messages: messages[start..end].to_vec(),
+        })
+    }
+
     // BROWSER CHAT MANAGEMENT
 
     #[http]
     async fn create_chat_link(&mut self, req: CreateChatLinkReq) -> Result<String, String> {
 
         let key = format!("{:x}", rand::random::<u128>());
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let timestamp = now_secs();
+
+        let guest_name = req.guest_name
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty());
+        let max_uses = if req.single_use { Some(1) } else { req.max_uses };
+        if max_uses == Some(0) {
+            return Err("max_uses must be at least 1".to_string());
+        }
+
+        let expires_at = match req.expires_in_seconds {
+            Some(secs) => Some(timestamp.checked_add(secs)
+                .ok_or_else(|| "Link expiry is too far in the future".to_string())?),
+            None => None,
+        };
 
         let chat_key = ChatKey {
             key: key.clone(),
-            user_name: format!("Guest-{}", rand::random::<u32>() % 10000),
+            user_name: guest_name.clone()
+                .unwrap_or_else(|| format!("Guest-{}", rand::random::<u32>() % 10000)),
             created_at: timestamp,
             is_revoked: false,
             chat_id: req.chat_id.clone(),
+            expires_at,
+            max_uses,
+            claimed_by: Vec::new(),
+            passphrase_hash: req.passphrase
+                .filter(|p| !p.is_empty())
+                .map(|p| hash_passphrase(&key, &p)),
+            name_locked: guest_name.is_some(),
+            uploaded_bytes: 0,
+            is_banned: false,
+            is_widget: false,
+            visitor_email: None,
+            away_replied_at: None,
+            upgraded_to: None,
         };
 
         self.chat_keys.insert(key.clone(), chat_key);
 
-        let link = format!("http://{}/public/join-{}", our().node, key);
+        let link = format!("{}/public/join-{}", self.public_base_url(), key);
         Ok(link)
     }
 
+    // Render a chat link, or our "message me" invite, as a QR code for in-person sharing
+    #[http]
+    async fn get_qr_code(&self, req: QrCodeReq) -> Result<QrCodeImage, String> {
+        let link = match req.link.as_deref().map(str::trim) {
+            Some(link) if !link.is_empty() => link.to_string(),
+            // Strangers can't open our app, but the widget page gives each of them a chat with us
+            _ if self.settings.widget.enabled => format!("{}/public/widget", self.public_base_url()),
+            // Otherwise invite other nodes to a P2P chat: our node name is what they start it with
+            _ => our().node.clone(),
+        };
+
+        let size = req.size.unwrap_or(QR_DEFAULT_SIZE).min(QR_MAX_SIZE);
+        let error_correction = match req.error_correction {
+            QrErrorCorrection::Low | QrErrorCorrection::Medium if req.with_icon => QrErrorCorrection::Quartile,
+            level => level,
+        };
+
+        let layout = QrLayout::new(&link, &error_correction, size, req.with_icon)?;
+        let (mime_type, data) = match req.format {
+            QrFormat::Png => ("image/png", layout.to_png()?),
+            QrFormat::Svg => ("image/svg+xml", layout.to_svg().into_bytes()),
+        };
+
+        Ok(QrCodeImage {
+            link,
+            mime_type: mime_type.to_string(),
+            size: layout.pixel_size(),
+            error_correction,
+            data_url: format!("data:{};base64,{}", mime_type, base64_encode(&data)),
+        })
+    }
+
     #[http]
     async fn get_chat_keys(&self) -> Result<Vec<ChatKey>, String> {
         Ok(self.chat_keys.values()
@@ -1277,6 +3006,84 @@ impl ChatState {
             })
     }
 
+    #[http]
+    async fn get_guest_sessions(&self) -> Result<Vec<GuestSession>, String> {
+        let mut sessions: Vec<GuestSession> = self.guest_sessions.values().cloned().collect();
+        sessions.sort_by(|a, b| b.connected_at.cmp(&a.connected_at));
+        Ok(sessions)
+    }
+
+    #[http]
+    async fn kick_guest_session(&mut self, req: KickGuestSessionReq) -> Result<String, String> {
+        if !self.guest_sessions.contains_key(&req.channel_id) {
+            return Err("Guest session not found".to_string());
+        }
+        self.disconnect_guest(req.channel_id, "You have been disconnected by the host");
+        Ok("Guest session closed".to_string())
+    }
+
+    // Unlike deleting the chat, a ban keeps the conversation; the link just stops working
+    #[http]
+    async fn ban_chat_key(&mut self, req: BanChatKeyReq) -> Result<String, String> {
+        let key = self.chat_keys.get_mut(&req.key)
+            .ok_or_else(|| "Chat key not found".to_string())?;
+        key.is_revoked = true;
+        key.is_banned = true;
+
+        let channels: Vec<u32> = self.guest_sessions.values()
+            .filter(|session| session.chat_key == req.key)
+            .map(|session| session.channel_id)
+            .collect();
+        for channel_id in channels {
+            self.disconnect_guest(channel_id, "You have been removed from this chat");
+        }
+        Ok("Chat key banned".to_string())
+    }
+
+    // Called on a host by our own node to take over a conversation we had there as a guest
+    #[http]
+    async fn claim_guest_chat(&mut self, req: ClaimGuestChatReq) -> Result<Chat, String> {
+        let host = self.resolve_counterparty(&req.host)?;
+        let target = Address::from((host.as_str(), OUR_PROCESS_ID));
+
+        let history = match upgrade_guest_chat_remote_rpc(&target, req.challenge.trim().to_string()).await {
+            Ok(Ok(history)) => history,
+            Ok(Err(e)) => return Err(e),
+            Err(e) => return Err(format!("Couldn't reach {}: {:?}", host, e)),
+        };
+        let history: Vec<ChatMessage> = history.into_iter()
+            .filter_map(|m| serde_json::to_value(&m).ok().and_then(|v| serde_json::from_value(v).ok()))
+            .collect();
+
+        Ok(self.import_upgraded_chat(&host, history))
+    }
+
+    // The networking layer authenticates the calling node, so a node that returns the
+    // challenge issued for it has proven the guest controls it
+    #[remote]
+    async fn upgrade_guest_chat(&mut self, challenge: String) -> Result<Vec<ChatMessage>, String> {
+        let node = source().node.clone();
+        self.check_rate(&node, InboundKind::Message)?;
+
+        let now = now_secs();
+        let upgrade = self.guest_upgrades.get(&challenge)
+            .filter(|upgrade| now < upgrade.created_at + GUEST_UPGRADE_TTL_SECS)
+            .cloned()
+            .ok_or_else(|| "Unknown or expired claim code".to_string())?;
+        if upgrade.node != node {
+            return Err("This claim code was issued for a different node".to_string());
+        }
+        self.guest_upgrades.remove(&challenge);
+
+        let key_data = self.chat_keys.get(&upgrade.chat_key)
+            .filter(|key| key.is_usable(now))
+            .cloned()
+            .ok_or_else(|| "Chat link is no longer valid".to_string())?;
+
+        let chat = self.merge_guest_chat(&key_data, &node);
+        Ok(chat.messages)
+    }
+
     // SETTINGS
 
     #[http]
@@ -1342,10 +3149,7 @@ impl ChatState {
                 node: our().node.clone(),
                 profile: self.profile.clone(),
             };
-            send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                mime: Some("application/json".to_string()),
-                bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-            });
+            push_json(channel_id, &msg);
         }
 
         // Notify all chat counterparties about the profile update
@@ -1382,258 +3186,237 @@ impl ChatState {
 
     #[http]
     async fn upload_file(&mut self, req: UploadFileReq) -> Result<ChatMessage, String> {
+        let timestamp = now_secs();
 
-        // Decode base64 data
-        let file_data = base64_decode(&req.data)
-            .map_err(|e| format!("Failed to decode base64: {}", e))?;
-
-        // Check file size limit
-        let file_size_mb = (file_data.len() as u64) / (1024 * 1024);
-        if file_size_mb > self.settings.max_file_size_mb {
-            return Err(format!("File size exceeds limit of {} MB", self.settings.max_file_size_mb));
-        }
+        let message_id = format!("{}:{}", timestamp, rand::random::<u32>());
 
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        // Messages due in the future wait in the scheduler
+        if let Some(send_at) = req.send_at.filter(|&send_at| send_at > timestamp) {
+            // Check the size and store the file now, so the scheduler only keeps a reference
+            let file_data = self.decode_upload(&req.data)?;
+            let stored = self.store_chat_file(
+                &req.chat_id,
+                &message_id,
+                &our().node,
+                &req.filename,
+                &req.mime_type,
+                &file_data,
+                timestamp,
+            )?;
+
+            let message_type = if req.mime_type.starts_with("image/") {
+                MessageType::Image
+            } else {
+                MessageType::File
+            };
 
-        let message_id = format!("{}:{}", timestamp, rand::random::<u32>());
+            let scheduled = ScheduledMessage {
+                id: message_id,
+                chat_id: req.chat_id,
+                send_at,
+                created_at: timestamp,
+                message_type,
+                content: req.filename.clone(),
+                reply_to: req.reply_to,
+                filename: Some(req.filename),
+                mime_type: Some(req.mime_type),
+                data: None,
+                duration: None,
+                file_id: Some(stored.file_id),
+            };
+            return Ok(self.schedule_message(scheduled));
+        }
 
-        // Determine message type based on mime type
-        let message_type = if req.mime_type.starts_with("image/") {
-            MessageType::Image
-        } else {
-            MessageType::File
-        };
+        self.upload_file_now(req, message_id)
+    }
 
-        // Store file in VFS
-        let package_id = our().package_id();
-        let _safe_filename = req.filename.replace("/", "_").replace("..", "_");
-        let file_id = format!("{}_{}", timestamp, rand::random::<u32>());
-        let vfs_path = format!("/{}/files/{}/{}",
-            package_id,
-            req.chat_id.replace(":", "_"),
-            file_id
-        );
+    #[http]
+    async fn send_voice_note(&mut self, req: SendVoiceNoteReq) -> Result<ChatMessage, String> {
+        let timestamp = now_secs();
 
-        // Create directory if it doesn't exist
-        let dir_path = format!("/{}/files/{}", package_id, req.chat_id.replace(":", "_"));
-        let _ = vfs::open_dir(&dir_path, true, Some(5));
-
-        // Create and write original file to VFS
-        let file = vfs::create_file(&vfs_path, Some(5))
-            .map_err(|e| format!("Failed to create VFS file: {:?}", e))?;
-        file.write(&file_data)
-            .map_err(|e| format!("Failed to write to VFS: {:?}", e))?;
-
-        // For images, use data URL (they're usually small enough)
-        // For other files, compress and send, or provide download link
-        let (file_url, compressed_data) = if message_type == MessageType::Image {
-            // Images: use data URL for easy inline display
-            (format!("data:{};base64,{}", req.mime_type, req.data), None)
-        } else {
-            // Files: compress and prepare for sending
-            let compressed = compress_data(&file_data)?;
-            let compressed_b64 = base64_encode(&compressed);
-
-            // Store compressed data for sending to counterparty
-            // But locally, we'll serve from VFS
-            let local_url = format!("/files/{}/{}", req.chat_id.replace(":", "_"), file_id);
-            (local_url, Some(compressed_b64))
-        };
+        let message_id = format!("{}:{}", timestamp, rand::random::<u32>());
 
-        let file_info = FileInfo {
-            filename: req.filename.clone(),
-            mime_type: req.mime_type.clone(),
-            size: file_data.len() as u64,
-            url: file_url.clone(),
-        };
+        // Messages due in the future wait in the scheduler
+        if let Some(send_at) = req.send_at.filter(|&send_at| send_at > timestamp) {
+            let scheduled = ScheduledMessage {
+                id: message_id,
+                chat_id: req.chat_id,
+                send_at,
+                created_at: timestamp,
+                message_type: MessageType::VoiceNote,
+                content: format!("Voice note ({}s)", req.duration),
+                reply_to: req.reply_to,
+                filename: None,
+                mime_type: Some("audio/webm".to_string()),
+                data: Some(req.audio_data),
+                duration: Some(req.duration),
+                file_id: None,
+            };
+            return Ok(self.schedule_message(scheduled));
+        }
 
-        let message = ChatMessage {
-            id: message_id,
-            sender: our().node.clone(),
-            content: req.filename,
-            timestamp,
-            status: MessageStatus::Sending,
-            reply_to: req.reply_to,
-            reactions: Vec::new(),
-            message_type: message_type.clone(),
-            file_info: Some(file_info),
-        };
+        self.send_voice_note_now(req, message_id)
+    }
 
-        // Add to chat
-        let chat = self.chats.entry(req.chat_id.clone()).or_insert_with(|| {
-            let counterparty = req.chat_id.split(':').nth(1).unwrap_or("unknown").to_string();
-            Chat {
-                id: req.chat_id.clone(),
-                counterparty,
-                messages: Vec::new(),
-                last_activity: timestamp,
-                unread_count: 0,
-                is_blocked: false,
-                notify: true,
-                counterparty_profile: None,
-            }
-        });
+    // SCHEDULED MESSAGES
 
-        chat.messages.push(message.clone());
-        chat.last_activity = timestamp;
+    #[http]
+    async fn get_scheduled_messages(&self, req: GetScheduledMessagesReq) -> Result<Vec<ScheduledMessage>, String> {
+        Ok(self.scheduled_messages.iter()
+            .filter(|m| req.chat_id.as_ref().map_or(true, |chat_id| &m.chat_id == chat_id))
+            .cloned()
+            .collect())
+    }
 
-        // Send to counterparty using generated RPC
-        let counterparty = chat.counterparty.clone();
-        let mut msg_to_send = message.clone();
+    #[http]
+    async fn edit_scheduled_message(&mut self, req: EditScheduledMessageReq) -> Result<ScheduledMessage, String> {
+        let scheduled = self.scheduled_messages.iter_mut()
+            .find(|m| m.id == req.id)
+            .ok_or_else(|| "Scheduled message not found".to_string())?;
 
-        // For files (not images), replace URL with compressed data for transmission
-        if message_type == MessageType::File {
-            if let Some(compressed) = compressed_data {
-                if let Some(ref mut file_info) = msg_to_send.file_info {
-                    file_info.url = format!("compressed:{}", compressed);
-                }
-            }
+        if scheduled.message_type != MessageType::Text {
+            return Err("Only text messages can be edited".to_string());
         }
 
-        let target = Address::from((counterparty.as_str(), OUR_PROCESS_ID));
+        scheduled.content = req.content;
+        let scheduled = scheduled.clone();
+        self.broadcast_scheduled_update();
 
-        // Send using generated RPC method
-        // Convert our local type to the generated type via JSON serialization
-        let msg_json = serde_json::to_value(&msg_to_send).unwrap();
-        let msg_for_rpc: CUChatMessage = serde_json::from_value(msg_json).unwrap();
-        match receive_message_remote_rpc(&target, msg_for_rpc).await {
-            Ok(_) => {
-                if let Some(chat) = self.chats.get_mut(&req.chat_id) {
-                    if let Some(msg) = chat.messages.iter_mut().find(|m| m.id == message.id) {
-                        msg.status = safe_update_message_status(&msg.status, MessageStatus::Sent);
-                    }
+        Ok(scheduled)
+    }
 
-                    // Send ChatUpdate with the updated message status
-                    for &channel_id in self.ws_connections.keys() {
-                        let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                        send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                            mime: Some("application/json".to_string()),
-                            bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                        });
-                    }
-                }
-            }
-            Err(_) => {
-                {
-                    let mut queue = self.delivery_queue.lock().unwrap();
-                    queue.entry(counterparty.clone())
-                        .or_insert_with(Vec::new)
-                        .push(msg_to_send);
-                }
+    #[http]
+    async fn reschedule_message(&mut self, req: RescheduleMessageReq) -> Result<ScheduledMessage, String> {
+        let scheduled = self.scheduled_messages.iter_mut()
+            .find(|m| m.id == req.id)
+            .ok_or_else(|| "Scheduled message not found".to_string())?;
+
+        // A time in the past sends the message on the next scheduler tick
+        scheduled.send_at = req.send_at;
+        let scheduled = scheduled.clone();
+        self.scheduled_messages.sort_by_key(|m| m.send_at);
+        self.broadcast_scheduled_update();
+
+        Ok(scheduled)
+    }
 
-                // Still broadcast NewMessage for failed sends
-                for &channel_id in self.ws_connections.keys() {
-                    let msg = WsServerMessage::NewMessage(message.clone());
-                    send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-                    });
-                }
-            }
+    #[http]
+    async fn cancel_scheduled_message(&mut self, req: CancelScheduledMessageReq) -> Result<String, String> {
+        let pos = self.scheduled_messages.iter()
+            .position(|m| m.id == req.id)
+            .ok_or_else(|| "Scheduled message not found".to_string())?;
+
+        let scheduled = self.scheduled_messages.remove(pos);
+        if let Some(file_id) = scheduled.file_id {
+            self.release_stored_files(vec![file_id]);
         }
+        self.broadcast_scheduled_update();
 
-        Ok(message)
+        Ok("Scheduled message cancelled".to_string())
     }
+
+    #[local]
     #[http]
-    async fn send_voice_note(&mut self, req: SendVoiceNoteReq) -> Result<ChatMessage, String> {
+    async fn release_scheduled_messages(&mut self) -> Result<u32, String> {
+        Ok(self.release_due_messages().await)
+    }
 
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    // UNDO SEND
 
-        let message_id = format!("{}:{}", timestamp, rand::random::<u32>());
+    #[http]
+    async fn cancel_pending_message(&mut self, req: CancelPendingMessageReq) -> Result<String, String> {
+        let pos = self.pending_sends.iter()
+            .position(|p| p.chat_id == req.chat_id && p.message.id == req.message_id)
+            .ok_or_else(|| "Message has already been sent".to_string())?;
+        self.pending_sends.remove(pos);
 
-        // Store voice note
-        let file_url = format!("data:audio/webm;base64,{}", req.audio_data);
+        if let Some(chat) = self.chats.get_mut(&req.chat_id) {
+            chat.messages.retain(|m| m.id != req.message_id);
 
-        let file_info = FileInfo {
-            filename: format!("voice_note_{}.webm", message_id),
-            mime_type: "audio/webm".to_string(),
-            size: req.audio_data.len() as u64,
-            url: file_url,
-        };
+            let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+            self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+        }
 
-        let message = ChatMessage {
-            id: message_id,
-            sender: our().node.clone(),
-            content: format!("Voice note ({}s)", req.duration),
-            timestamp,
-            status: MessageStatus::Sending,
-            reply_to: req.reply_to,
-            reactions: Vec::new(),
-            message_type: MessageType::VoiceNote,
-            file_info: Some(file_info),
-        };
+        self.forget_message(&req.chat_id, &req.message_id);
 
-        // Add to chat
-        let chat = self.chats.entry(req.chat_id.clone()).or_insert_with(|| {
-            let counterparty = req.chat_id.split(':').nth(1).unwrap_or("unknown").to_string();
-            Chat {
-                id: req.chat_id.clone(),
-                counterparty,
-                messages: Vec::new(),
-                last_activity: timestamp,
-                unread_count: 0,
-                is_blocked: false,
-                notify: true,
-                counterparty_profile: None,
-            }
-        });
+        Ok("Message cancelled".to_string())
+    }
 
-        chat.messages.push(message.clone());
-        chat.last_activity = timestamp;
+    #[local]
+    async fn flush_notifications(&mut self, chat_id: String, opened_at: u64) -> Result<(), String> {
+        // A window opened after this flush was scheduled belongs to a later flush
+        if self.notification_windows.get(&chat_id).map_or(true, |window| window.opened_at != opened_at) {
+            return Ok(());
+        }
+        if let Some(window) = self.notification_windows.remove(&chat_id) {
+            self.push_held_notifications(&chat_id, window);
+        }
 
-        // Send to counterparty using generated RPC
-        let counterparty = chat.counterparty.clone();
-        let msg_to_send = message.clone();
+        Ok(())
+    }
 
-        let target = Address::from((counterparty.as_str(), OUR_PROCESS_ID));
+    #[local]
+    async fn send_notification_digest(&mut self) -> Result<u32, String> {
+        let interval_secs = self.settings.notification_rules.digest_interval_minutes as u64 * 60;
+        let now = now_secs();
+        if self.notification_digest.is_empty() || now < self.last_digest_at + interval_secs {
+            return Ok(0);
+        }
+        // Hold the digest until quiet hours are over
+        if self.settings.notification_rules.in_quiet_hours(now) {
+            return Ok(0);
+        }
 
-        // Send using generated RPC method
-        // Convert our local type to the generated type via JSON serialization
-        let msg_json = serde_json::to_value(&msg_to_send).unwrap();
-        let msg_for_rpc: CUChatMessage = serde_json::from_value(msg_json).unwrap();
-        match receive_message_remote_rpc(&target, msg_for_rpc).await {
-            Ok(_) => {
-                if let Some(chat) = self.chats.get_mut(&req.chat_id) {
-                    if let Some(msg) = chat.messages.iter_mut().find(|m| m.id == message.id) {
-                        msg.status = safe_update_message_status(&msg.status, MessageStatus::Sent);
-                    }
+        self.last_digest_at = now;
+        let mut entries: Vec<(String, u32)> = self.notification_digest.drain().collect();
+        // The user is looking at the app, so there is nothing to catch up on
+        if !self.active_connections.is_empty() {
+            return Ok(0);
+        }
+        entries.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let total: u32 = entries.iter().map(|(_, count)| count).sum();
+        let summary: Vec<String> = entries.iter()
+            .map(|(chat_id, count)| {
+                let name = match self.chats.get(chat_id) {
+                    Some(chat) => self.display_name(&chat.counterparty),
+                    None => chat_id.clone(),
+                };
+                format!("{} ({})", name, count)
+            })
+            .collect();
+        let title = if entries.len() == 1 {
+            format!("{} new messages in 1 chat", total)
+        } else {
+            format!("{} new messages in {} chats", total, entries.len())
+        };
+        let body = truncate_chars(&summary.join(", "), 100);
+        let chat_id = if entries.len() == 1 { entries[0].0.clone() } else { String::new() };
 
-                    // Send ChatUpdate with the updated message status
-                    for &channel_id in self.ws_connections.keys() {
-                        let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                        send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                            mime: Some("application/json".to_string()),
-                            bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                        });
-                    }
-                }
-            }
-            Err(_) => {
-                {
-                    let mut queue = self.delivery_queue.lock().unwrap();
-                    queue.entry(counterparty.clone())
-                        .or_insert_with(Vec::new)
-                        .push(msg_to_send);
-                }
+        spawn(async move {
+            send_push_notification_for_message("", &title, &body, &chat_id).await;
+        });
 
-                // Still broadcast NewMessage for failed sends
-                for &channel_id in self.ws_connections.keys() {
-                    let msg = WsServerMessage::NewMessage(message.clone());
-                    send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-                    });
-                }
-            }
-        }
+        Ok(total)
+    }
+
+    #[http]
+    async fn set_chat_priority(&mut self, req: SetChatPriorityReq) -> Result<Chat, String> {
+        self.update_chat(&req.chat_id, |chat| chat.low_priority = req.low_priority)
+    }
+
+    #[local]
+    async fn release_pending_message(&mut self, chat_id: String, message_id: String) -> Result<(), String> {
+        // Already cancelled or released
+        let pos = match self.pending_sends.iter().position(|p| p.chat_id == chat_id && p.message.id == message_id) {
+            Some(pos) => pos,
+            None => return Ok(()),
+        };
+
+        let pending = self.pending_sends.remove(pos);
+        self.dispatch_message(&pending.chat_id, pending.message);
 
-        Ok(message)
+        Ok(())
     }
 
     // P2P MESSAGE RECEIVING
@@ -1644,26 +3427,20 @@ impl ChatState {
 
         // Normalize chat ID to always be alphabetically sorted
         let chat_id = Self::normalize_chat_id(&counterparty, &our().node);
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let timestamp = now_secs();
 
         // Check if chat already exists
         let chat_exists = self.chats.contains_key(&chat_id);
         if !chat_exists {
             // Get counterparty profile if we have it
             let counterparty_profile = self.node_profiles.get(&counterparty).cloned();
+            let is_request = self.is_unsolicited(&counterparty);
 
             let chat = Chat {
-                id: chat_id.clone(),
-                counterparty: counterparty.clone(),
-                messages: Vec::new(),
-                last_activity: timestamp,
-                unread_count: 0,
-                is_blocked: false,
-                notify: true,
                 counterparty_profile,
+                counterparty_nickname: self.contacts.get(&counterparty).and_then(|c| c.nickname.clone()),
+                is_request,
+                ..Chat::new(chat_id.clone(), counterparty.clone(), timestamp)
             };
 
             self.chats.insert(chat_id.clone(), chat.clone());
@@ -1674,10 +3451,7 @@ impl ChatState {
             for &channel_id in self.ws_connections.keys() {
                 println!("receive_chat_creation: Sending ChatUpdate to channel {}", channel_id);
                 let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                    mime: Some("application/json".to_string()),
-                    bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                });
+                push_json(channel_id, &chat_update);
             }
         } else {
             println!("receive_chat_creation: Chat {} already exists", chat_id);
@@ -1742,20 +3516,84 @@ impl ChatState {
 
     #[remote]
     async fn receive_message(&mut self, message: ChatMessage) -> Result<(), String> {
-        // Find or create chat for this message - normalize the ID
-        let chat_id = Self::normalize_chat_id(&message.sender, &our().node);
+        // Limits are keyed on the authenticated caller, never on names taken from the payload
+        let source_node = source().node.clone();
+        self.check_rate(&source_node, InboundKind::Message)?;
+        if message.content.len() > MAX_INBOUND_CONTENT_BYTES {
+            return Err(self.reject(
+                &source_node,
+                InboundViolation::ContentTooLong,
+                format!("Message content is {} bytes, limit is {}", message.content.len(), MAX_INBOUND_CONTENT_BYTES),
+                None,
+            ));
+        }
+
+        // The chat, quota and file index all go by the sender, so it has to be the caller
+        if message.sender != source_node {
+            return Err(self.reject(
+                &source_node,
+                InboundViolation::SenderMismatch,
+                format!("Message names {} as its sender", message.sender),
+                None,
+            ));
+        }
+
+        // A disappearing message that sat in the sender's delivery queue past its expiry
+        // is acknowledged so it leaves their queue, but never stored
+        let now = now_secs();
+        if message.expires_at.map_or(false, |expires_at| expires_at <= now) {
+            println!("Dropping expired message {} from {}", message.id, message.sender);
+            let target = Address::from((message.sender.as_str(), OUR_PROCESS_ID));
+            let _ = receive_message_ack_remote_rpc(&target, message.id.clone()).await;
+            return Ok(());
+        }
+
+        // Messages from a node we blocked are dropped without an acknowledgment. The sender
+        // was checked against the caller above, so a blocked node can't name another chat.
+        let chat_id = Self::normalize_chat_id(&source_node, &our().node);
+        if self.chats.get(&chat_id).map_or(false, |chat| chat.is_blocked) {
+            println!("Dropping message {} from blocked node {}", message.id, source_node);
+            return Ok(());
+        }
+
+        // Refuse attachments that would take this counterparty over its storage quota
+        let quota_bytes = self.settings.inbound_quota_mb * 1024 * 1024;
+        if let Some(ref file_info) = message.file_info {
+            if quota_bytes > 0 && self.inbound_bytes_from(&source_node) + file_info.size > quota_bytes {
+                println!("Rejecting attachment from {}: inbound quota exceeded", message.sender);
+                return Err("Inbound attachment quota exceeded".to_string());
+            }
+        }
+
+        // Decode the attachment before touching any chat, so a rejected one leaves nothing behind
+        let file_data = match message.file_info {
+            Some(ref file_info) => {
+                let max_bytes = self.settings.max_file_size_mb * 1024 * 1024;
+                let file_data = match Self::decode_inbound_file(&file_info.url, max_bytes) {
+                    Ok(file_data) => file_data,
+                    Err(e) => return Err(self.reject(&source_node, InboundViolation::AttachmentTooLarge, e, None)),
+                };
+
+                // The declared size can't be trusted, so check the decoded size as well
+                if quota_bytes > 0 && self.inbound_bytes_from(&source_node) + file_data.len() as u64 > quota_bytes {
+                    println!("Rejecting attachment from {}: inbound quota exceeded", message.sender);
+                    return Err("Inbound attachment quota exceeded".to_string());
+                }
+                file_data
+            }
+            None => Vec::new(),
+        };
+
+        // Find or create chat for this message
         let is_new_chat = !self.chats.contains_key(&chat_id);
+        let is_request = self.is_unsolicited(&message.sender);
 
-        let chat = self.chats.entry(chat_id.clone()).or_insert_with(|| {
+        self.chats.entry(chat_id.clone()).or_insert_with(|| {
             Chat {
-                id: chat_id.clone(),
-                counterparty: message.sender.clone(),
-                messages: Vec::new(),
-                last_activity: message.timestamp,
-                unread_count: 0,
-                is_blocked: false,
-                notify: true,
                 counterparty_profile: self.node_profiles.get(&message.sender).cloned(),
+                counterparty_nickname: self.contacts.get(&message.sender).and_then(|c| c.nickname.clone()),
+                is_request,
+                ..Chat::new(chat_id.clone(), message.sender.clone(), message.timestamp)
             }
         });
 
@@ -1768,114 +3606,78 @@ impl ChatState {
             let is_image = updated_message.message_type == MessageType::Image;
             let original_url = file_info.url.clone();
 
-            let file_data = if file_info.url.starts_with("compressed:") {
-                // Handle compressed file data
-                let compressed_b64 = &file_info.url[11..]; // Skip "compressed:" prefix
-
-                // Decode base64
-                let compressed_data = match base64_decode(compressed_b64) {
-                    Ok(data) => data,
-                    Err(e) => {
-                        println!("Failed to decode compressed file: {}", e);
-                        vec![]
-                    }
-                };
-
-                // Decompress
-                match decompress_data(&compressed_data) {
-                    Ok(data) => data,
-                    Err(e) => {
-                        println!("Failed to decompress file: {}", e);
-                        vec![]
-                    }
-                }
-            } else if file_info.url.starts_with("data:") {
-                // Handle data URL (for images)
-                if let Some(comma_pos) = file_info.url.find(',') {
-                    let base64_data = &file_info.url[comma_pos + 1..];
-
-                    // Decode base64
-                    match base64_decode(base64_data) {
-                        Ok(data) => data,
-                        Err(e) => {
-                            println!("Failed to decode file data: {}", e);
-                            vec![]
+            if !file_data.is_empty() {
+                // Save to VFS and record it in the file index
+                match self.store_chat_file(
+                    &chat_id,
+                    &updated_message.id,
+                    &source_node,
+                    &file_info.filename,
+                    &file_info.mime_type,
+                    &file_data,
+                    updated_message.timestamp,
+                ) {
+                    Ok(stored) => {
+                        println!("Saved received file {} to VFS at {}", file_info.filename, stored.vfs_path);
+
+                        // For images, keep the data URL for inline display
+                        // For files, update to local VFS path
+                        if is_image {
+                            // Keep the original data URL for images
+                            file_info.url = original_url;
+                        } else {
+                            // Update the file URL to point to our local VFS path
+                            file_info.url = Self::file_url(&stored);
                         }
                     }
-                } else {
-                    vec![]
-                }
-            } else {
-                vec![]
-            };
-
-            if !file_data.is_empty() {
-                // Save to VFS
-                let package_id = our().package_id();
-                let file_id = format!("{}_{}", updated_message.timestamp, rand::random::<u32>());
-                let vfs_path = format!("/{}/files/{}/{}",
-                    package_id,
-                    chat_id.replace(":", "_"),
-                    file_id
-                );
-
-                // Create directory if it doesn't exist
-                let dir_path = format!("/{}/files/{}", package_id, chat_id.replace(":", "_"));
-                let _ = vfs::open_dir(&dir_path, true, Some(5));
-
-                // Create and write file
-                if let Ok(file) = vfs::create_file(&vfs_path, Some(5)) {
-                    let _ = file.write(&file_data);
-                    println!("Saved received file {} to VFS at {}", file_info.filename, vfs_path);
-
-                    // For images, keep the data URL for inline display
-                    // For files, update to local VFS path
-                    if is_image {
-                        // Keep the original data URL for images
-                        file_info.url = original_url;
-                    } else {
-                        // Update the file URL to point to our local VFS path
-                        file_info.url = format!("/files/{}/{}", chat_id.replace(":", "_"), file_id);
+                    Err(e) => {
+                        println!("Failed to save received file {}: {}", file_info.filename, e);
                     }
                 }
             }
         }
 
+        let chat = self.chats.get_mut(&chat_id).unwrap();
+
         // Add message to chat
         chat.messages.push(updated_message.clone());
         chat.last_activity = updated_message.timestamp;
         chat.unread_count += 1;
 
+        // New activity brings an archived chat back unless the user wants it to stay archived
+        let unarchived = chat.is_archived && !self.settings.keep_archived;
+        if unarchived {
+            chat.is_archived = false;
+        }
+
         // Send to WebSocket connections if any
         for &channel_id in self.ws_connections.keys() {
             // If this is a new chat, send ChatUpdate first
-            if is_new_chat {
+            if is_new_chat || unarchived {
                 let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                    mime: Some("application/json".to_string()),
-                    bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                });
+                push_json(channel_id, &chat_update);
             }
 
             // Then send the new message
             let msg = WsServerMessage::NewMessage(updated_message.clone());
-            send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                mime: Some("application/json".to_string()),
-                bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-            });
+            push_json(channel_id, &msg);
         }
 
-        // Send push notification if user has notifications enabled AND no active connections
+        // Message requests stay quiet: no notification until accepted. Delivery is still
+        // acknowledged so the sender sees Delivered; only reading is kept from them.
+        let is_request = chat.is_request;
+
         // We only send notifications if the user is not actively viewing the app
-        if chat.notify && self.settings.notify_chats && self.active_connections.is_empty() {
-            // Try to send a push notification
-            spawn(async move {
-                send_push_notification_for_message(
-                    &updated_message.sender,
-                    &updated_message.content,
-                    &chat_id
-                ).await;
-            });
+        if !is_request && self.active_connections.is_empty() {
+            match self.notification_for(&chat_id, &updated_message, now) {
+                NotifyDecision::Push { sender_name, body } => {
+                    self.push_coalesced(&chat_id, &updated_message.sender, sender_name, body, now);
+                }
+                NotifyDecision::Digest => {
+                    *self.notification_digest.entry(chat_id.clone()).or_insert(0) += 1;
+                }
+                NotifyDecision::Silent => {}
+            }
         }
 
         // Send acknowledgment back to sender using generated RPC
@@ -1895,10 +3697,30 @@ impl ChatState {
     async fn receive_reaction(&mut self, message_id: String, emoji: String, user: String) -> Result<(), String> {
         println!("Received reaction {} from {} for message {}", emoji, user, message_id);
 
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let source_node = source().node.clone();
+        self.check_rate(&source_node, InboundKind::Reaction)?;
+        if emoji.chars().count() > MAX_EMOJI_CHARS {
+            return Err(self.reject(
+                &source_node,
+                InboundViolation::EmojiTooLong,
+                format!("Reaction is {} characters, limit is {}", emoji.chars().count(), MAX_EMOJI_CHARS),
+                None,
+            ));
+        }
+        let reaction_count = self.chats.values()
+            .flat_map(|chat| chat.messages.iter())
+            .find(|m| m.id == message_id)
+            .map_or(0, |m| m.reactions.len());
+        if reaction_count >= MAX_REACTIONS_PER_MESSAGE {
+            return Err(self.reject(
+                &source_node,
+                InboundViolation::TooManyReactions,
+                format!("Message already has {} reactions", reaction_count),
+                None,
+            ));
+        }
+
+        let timestamp = now_secs();
 
         let reaction = MessageReaction {
             emoji: emoji.clone(),
@@ -1916,10 +3738,7 @@ impl ChatState {
                     // Send ChatUpdate to WebSocket connections
                     for &channel_id in self.ws_connections.keys() {
                         let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                        send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                            mime: Some("application/json".to_string()),
-                            bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                        });
+                        push_json(channel_id, &chat_update);
                     }
                     return Ok(());
                 }
@@ -1950,10 +3769,7 @@ impl ChatState {
                 for &channel_id in self.ws_connections.keys() {
                     println!("Sending ChatUpdate for delivered message to channel {}", channel_id);
                     let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                    send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                    });
+                    push_json(channel_id, &chat_update);
                 }
                 return Ok(());
             }
@@ -1971,28 +3787,106 @@ impl ChatState {
         if let Some(chat) = self.chats.get_mut(&chat_id) {
             if let Some(pos) = chat.messages.iter().position(|m| m.id == message_id) {
                 chat.messages.remove(pos);
+                chat.pinned_messages.retain(|p| p.message_id != message_id);
                 println!("Deleted message {} from chat {}", message_id, chat_id);
 
                 // Notify all WebSocket connections about the updated chat
                 for &channel_id in self.ws_connections.keys() {
                     let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                    send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                    });
+                    push_json(channel_id, &chat_update);
                 }
+
+                self.forget_message(&chat_id, &message_id);
             }
         }
 
         Ok(())
     }
 
+    #[remote]
+    async fn receive_disappearing_timer(&mut self, _node: String, seconds: Option<u64>, changed_at: u64) -> Result<(), String> {
+        // The chat is always the one with the calling node, whatever the payload names
+        let source_node = source().node.clone();
+        println!("Received disappearing timer {:?} from {}", seconds, source_node);
+        self.check_rate(&source_node, InboundKind::ChatSetting)?;
+
+        if seconds.is_some_and(|seconds| seconds == 0 || seconds > MAX_DISAPPEARING_TIMER_SECS) {
+            return Err("Invalid disappearing timer".to_string());
+        }
+
+        // A change dated far ahead would win every later change and lock the timer
+        let now = now_secs();
+        if changed_at > now.saturating_add(MAX_CLOCK_SKEW_SECS) {
+            return Err(self.reject(
+                &source_node,
+                InboundViolation::TimestampInFuture,
+                format!("Timer change is dated {}s ahead", changed_at - now),
+                None,
+            ));
+        }
+
+        let chat_id = Self::normalize_chat_id(&source_node, &our().node);
+        let is_newer = match self.chats.get(&chat_id) {
+            Some(chat) => timer_change_wins(chat.disappearing_timer_updated_at, changed_at, &source_node, &our().node),
+            None => return Err("Chat not found".to_string()),
+        };
+
+        if is_newer {
+            self.update_disappearing_timer(&chat_id, seconds, changed_at, &source_node);
+        }
+
+        Ok(())
+    }
+
+    #[remote]
+    async fn receive_pin_update(&mut self, _node: String, message_id: String, pinned: bool, timestamp: u64) -> Result<(), String> {
+        // The chat and the recorded pinner are always the calling node, whatever the payload names
+        let source_node = source().node.clone();
+        println!("Received pin update from {} for message {}: {}", source_node, message_id, pinned);
+        self.check_rate(&source_node, InboundKind::ChatSetting)?;
+
+        let chat_id = Self::normalize_chat_id(&source_node, &our().node);
+        let chat = self.chats.get_mut(&chat_id)
+            .ok_or_else(|| "Chat not found".to_string())?;
+
+        if Self::apply_pin(chat, &message_id, pinned, &source_node, timestamp) {
+            let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+            self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+        }
+
+        Ok(())
+    }
+
     #[remote]
     async fn receive_profile_update(&mut self, node: String, profile: UserProfile) -> Result<(), String> {
-        println!("Received profile update from {}: {:?}", node, profile);
+        println!("Received profile update from {}: {:?}", node, profile.name);
+
+        let source_node = source().node.clone();
+        self.check_rate(&source_node, InboundKind::Profile)?;
+        if profile.name.chars().count() > MAX_PROFILE_NAME_CHARS {
+            return Err(self.reject(
+                &source_node,
+                InboundViolation::ProfileNameTooLong,
+                format!("Profile name is {} characters, limit is {}", profile.name.chars().count(), MAX_PROFILE_NAME_CHARS),
+                None,
+            ));
+        }
+        let pic_len = profile.profile_pic.as_ref().map_or(0, |pic| pic.len());
+        if pic_len > MAX_PROFILE_PIC_BYTES {
+            return Err(self.reject(
+                &source_node,
+                InboundViolation::ProfilePicTooLarge,
+                format!("Profile picture is {} bytes, limit is {}", pic_len, MAX_PROFILE_PIC_BYTES),
+                None,
+            ));
+        }
 
         // Store the profile
         self.node_profiles.insert(node.clone(), profile.clone());
+        if let Some(contact) = self.contacts.get_mut(&node) {
+            contact.profile = Some(profile.clone());
+            self.broadcast_contacts_update();
+        }
 
         // Update all chats with this counterparty
         for chat in self.chats.values_mut() {
@@ -2002,79 +3896,2457 @@ impl ChatState {
                 // Notify all WebSocket connections about the updated chat
                 for &channel_id in self.ws_connections.keys() {
                     let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                    send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                    });
+                    push_json(channel_id, &chat_update);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // PUBLIC BROWSER CHAT ENDPOINTS
+
+    #[http(path = "/public")]
+    async fn serve_public_chat(&self) -> Result<String, String> {
+        // Serve the browser chat HTML
+        Ok(include_str!("../../ui/public/browser-chat.html").to_string())
+    }
+
+    #[http(path = "/public/join-*")]
+    async fn serve_join_link(&self) -> Result<String, String> {
+        // Serve the browser chat HTML for join links
+        Ok(include_str!("../../ui/public/browser-chat.html").to_string())
+    }
+
+    #[http(path = "/public/widget.js")]
+    async fn serve_widget_script(&self) -> Result<String, String> {
+        add_response_header("Content-Type".to_string(), "application/javascript".to_string());
+        add_response_header("Cache-Control".to_string(), "public, max-age=3600".to_string());
+        Ok(include_str!("../../ui/public/widget.js").to_string())
+    }
+
+    #[http(path = "/public/widget")]
+    async fn serve_widget_page(&self) -> Result<String, String> {
+        let widget = &self.settings.widget;
+        if !widget.enabled {
+            set_response_status(StatusCode::NOT_FOUND);
+            return Err("The chat widget is disabled".to_string());
+        }
+
+        // The browser enforces allowed origins by refusing to frame the page anywhere else
+        let origins: Vec<&str> = widget.allowed_origins.iter()
+            .map(|origin| origin.trim().trim_end_matches('/'))
+            .filter(|origin| !origin.is_empty() && !origin.contains(|c: char| c == ';' || c.is_whitespace()))
+            .collect();
+        let frame_ancestors = if origins.is_empty() { "'none'".to_string() } else { origins.join(" ") };
+        add_response_header(
+            "Content-Security-Policy".to_string(),
+            format!("frame-ancestors {}", frame_ancestors),
+        );
+
+        let now = now_secs();
+        let config = WidgetPageConfig {
+            title: widget.title.clone()
+                .filter(|title| !title.trim().is_empty())
+                .unwrap_or_else(|| self.profile.name.clone()),
+            greeting: widget.greeting.clone(),
+            ask_name: widget.ask_name,
+            ask_email: widget.ask_email,
+            open: widget.is_open(now),
+            away_message: widget.away_message.clone(),
+            ticket: self.widget_ticket(now + WIDGET_TICKET_TTL_SECS),
+        };
+        // Escape '<' so owner-provided text can't close the script tag
+        let config = serde_json::to_string(&config).unwrap().replace('<', "\\u003c");
+
+        let page = include_str!("../../ui/public/browser-chat.html");
+        Ok(page.replacen(
+            "<script>",
+            &format!("<script>window.WIDGET_CONFIG = {};</script>\n    <script>", config),
+            1,
+        ))
+    }
+
+    #[http(path = "/files/*")]
+    async fn serve_file(&self) -> Result<Vec<u8>, String> {
+        // Path should be /files/<chat_dir>/<file_id>
+        let request_path = get_path().ok_or_else(|| "No request path provided".to_string())?;
+        let path_segments: Vec<&str> = request_path.split('/')
+            .filter(|s| !s.is_empty())
+            .skip_while(|s| *s != "files")
+            .collect();
+        if path_segments.len() < 3 {
+            set_response_status(StatusCode::BAD_REQUEST);
+            return Err("Invalid file path".to_string());
+        }
+
+        // Accept both the raw chat ID and the ':'-free form used on disk
+        let chat_dir = Self::chat_dir(path_segments[1]);
+        let file_id = path_segments[2];
+
+        // Files stored before the index existed are still served, with a guessed type
+        let (vfs_path, filename, mime_type, etag) = match self.file_index.get(file_id) {
+            Some(stored) if Self::chat_dir(&stored.chat_id) == chat_dir => (
+                stored.vfs_path.clone(),
+                stored.filename.clone(),
+                stored.mime_type.clone(),
+                Some(stored.etag.clone()),
+            ),
+            Some(_) => {
+                set_response_status(StatusCode::NOT_FOUND);
+                return Err("File not found".to_string());
+            }
+            None => (
+                format!("/{}/files/{}/{}", our().package_id(), chat_dir, file_id),
+                file_id.to_string(),
+                guess_mime_type(file_id).to_string(),
+                None,
+            ),
+        };
+
+        Self::respond_with_file(&vfs_path, &filename, &mime_type, etag.as_deref())
+    }
+
+    #[http(path = "/public/files/*")]
+    async fn serve_guest_file(&self) -> Result<Vec<u8>, String> {
+        // Path should be /public/files/<chat_key>/<file_id>/<expires>/<signature>
+        let request_path = get_path().ok_or_else(|| "No request path provided".to_string())?;
+        let path_segments: Vec<&str> = request_path.split('/')
+            .filter(|s| !s.is_empty())
+            .skip_while(|s| *s != "files")
+            .collect();
+        if path_segments.len() < 5 {
+            set_response_status(StatusCode::BAD_REQUEST);
+            return Err("Invalid file path".to_string());
+        }
+
+        let chat_key = path_segments[1];
+        let file_id = path_segments[2];
+        let expires: u64 = path_segments[3].parse().map_err(|_| {
+            set_response_status(StatusCode::BAD_REQUEST);
+            "Invalid expiry".to_string()
+        })?;
+        let signature = path_segments[4];
+
+        let now = now_secs();
+
+        if !self.verify_guest_file(chat_key, file_id, expires, signature) || expires < now {
+            set_response_status(StatusCode::FORBIDDEN);
+            return Err("Invalid or expired file link".to_string());
+        }
+
+        // Revoking or expiring the chat link also cuts off its file URLs
+        let key_data = match self.chat_keys.get(chat_key) {
+            Some(key_data) if key_data.is_usable(now) => key_data,
+            _ => {
+                set_response_status(StatusCode::FORBIDDEN);
+                return Err("Chat link is no longer valid".to_string());
+            }
+        };
+
+        let stored = match self.file_index.get(file_id) {
+            Some(stored) if stored.chat_id == key_data.chat_id => stored,
+            _ => {
+                set_response_status(StatusCode::NOT_FOUND);
+                return Err("File not found".to_string());
+            }
+        };
+
+        Self::respond_with_file(&stored.vfs_path, &stored.filename, &stored.mime_type, Some(&stored.etag))
+    }
+
+    // Caching, conditional and range handling shared by the file endpoints
+    fn respond_with_file(vfs_path: &str, filename: &str, mime_type: &str, etag: Option<&str>) -> Result<Vec<u8>, String> {
+        // Stored files never change, so they can be cached indefinitely
+        add_response_header("Cache-Control".to_string(), "private, max-age=31536000, immutable".to_string());
+        add_response_header("Accept-Ranges".to_string(), "bytes".to_string());
+        if let Some(etag) = etag {
+            add_response_header("ETag".to_string(), etag.to_string());
+            if let Some(if_none_match) = get_request_header("If-None-Match") {
+                if if_none_match.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*") {
+                    set_response_status(StatusCode::NOT_MODIFIED);
+                    return Ok(Vec::new());
+                }
+            }
+        }
+
+        // Read file from VFS
+        let file = vfs::open_file(vfs_path, false, Some(5))
+            .map_err(|e| {
+                set_response_status(StatusCode::NOT_FOUND);
+                format!("Failed to open file: {:?}", e)
+            })?;
+
+        let file_data = file.read()
+            .map_err(|e| format!("Failed to read file: {:?}", e))?;
+
+        add_response_header("Content-Type".to_string(), mime_type.to_string());
+        add_response_header("Content-Disposition".to_string(), content_disposition(filename, mime_type));
+
+        // Serve partial content so audio and video can seek
+        let total_len = file_data.len() as u64;
+        if let Some(range_header) = get_request_header("Range") {
+            // Only honour If-Range when it still matches the current version of the file
+            let range_applies = match get_request_header("If-Range") {
+                Some(if_range) => etag == Some(if_range.trim()),
+                None => true,
+            };
+
+            if range_applies {
+                match parse_byte_range(&range_header, total_len) {
+                    Ok(Some((start, end))) => {
+                        set_response_status(StatusCode::PARTIAL_CONTENT);
+                        add_response_header(
+                            "Content-Range".to_string(),
+                            format!("bytes {}-{}/{}", start, end, total_len),
+                        );
+                        return Ok(file_data[start as usize..=end as usize].to_vec());
+                    }
+                    Ok(None) => {}
+                    Err(()) => {
+                        set_response_status(StatusCode::RANGE_NOT_SATISFIABLE);
+                        add_response_header("Content-Range".to_string(), format!("bytes */{}", total_len));
+                        return Ok(Vec::new());
+                    }
+                }
+            }
+        }
+
+        Ok(file_data)
+    }
+    // DIAGNOSTICS
+
+    #[http]
+    async fn get_diagnostics(&self) -> Result<Diagnostics, String> {
+        let mut inbound_violations = self.inbound_violations.clone();
+        inbound_violations.sort_by(|a, b| b.count.cmp(&a.count));
+        let delivery_queue_len = self.delivery_queue.lock().unwrap()
+            .values()
+            .map(|messages| messages.len() as u32)
+            .sum();
+
+        Ok(Diagnostics {
+            total_violations: inbound_violations.iter().map(|v| v.count).sum(),
+            inbound_violations,
+            delivery_queue_len,
+            online_nodes: self.online_nodes.len() as u32,
+            ws_connections: self.ws_connections.len() as u32,
+        })
+    }
+
+    #[http]
+    async fn reset_diagnostics(&mut self) -> Result<String, String> {
+        self.inbound_violations.clear();
+        Ok("Diagnostics reset".to_string())
+    }
+
+    // CONTACTS
+
+    #[http]
+    async fn get_contacts(&self) -> Result<Vec<Contact>, String> {
+        Ok(self.sorted_contacts(self.contacts.values().cloned().collect()))
+    }
+
+    #[http]
+    async fn add_contact(&mut self, req: AddContactReq) -> Result<Contact, String> {
+        let node = req.node.trim().to_string();
+        if node.is_empty() || node == our().node {
+            return Err("Invalid contact node".to_string());
+        }
+        if self.contacts.contains_key(&node) {
+            return Err("Contact already exists".to_string());
+        }
+
+        let contact = Contact {
+            node: node.clone(),
+            nickname: normalize_nickname(req.nickname),
+            notes: req.notes.unwrap_or_default(),
+            favorite: req.favorite,
+            profile: self.node_profiles.get(&node).cloned(),
+            added_at: now_secs(),
+        };
+        self.contacts.insert(node.clone(), contact.clone());
+        self.sync_contact_nickname(&node);
+        self.broadcast_contacts_update();
+
+        Ok(contact)
+    }
+
+    #[http]
+    async fn update_contact(&mut self, req: UpdateContactReq) -> Result<Contact, String> {
+        let contact = self.contacts.get_mut(&req.node)
+            .ok_or_else(|| "Contact not found".to_string())?;
+        if req.nickname.is_some() {
+            contact.nickname = normalize_nickname(req.nickname);
+        }
+        if let Some(notes) = req.notes {
+            contact.notes = notes;
+        }
+        if let Some(favorite) = req.favorite {
+            contact.favorite = favorite;
+        }
+        let contact = contact.clone();
+
+        self.sync_contact_nickname(&req.node);
+        self.broadcast_contacts_update();
+
+        Ok(contact)
+    }
+
+    #[http]
+    async fn remove_contact(&mut self, req: RemoveContactReq) -> Result<String, String> {
+        self.contacts.remove(&req.node)
+            .ok_or_else(|| "Contact not found".to_string())?;
+
+        self.sync_contact_nickname(&req.node);
+        self.broadcast_contacts_update();
+
+        Ok("Contact removed".to_string())
+    }
+
+    #[http]
+    async fn search_contacts(&self, req: SearchContactsReq) -> Result<Vec<Contact>, String> {
+        let query = req.query.to_lowercase();
+        let results: Vec<Contact> = self.contacts.values()
+            .filter(|contact| {
+                contact.node.to_lowercase().contains(&query) ||
+                contact.nickname.as_ref().map_or(false, |n| n.to_lowercase().contains(&query)) ||
+                contact.profile.as_ref().map_or(false, |p| p.name.to_lowercase().contains(&query)) ||
+                contact.notes.to_lowercase().contains(&query)
+            })
+            .cloned()
+            .collect();
+
+        Ok(self.sorted_contacts(results))
+    }
+
+    // SEARCH
+
+    #[http]
+    async fn search_chats(&self, req: SearchChatsReq) -> Result<Vec<Chat>, String> {
+
+        let query = req.query.to_lowercase();
+        let results: Vec<Chat> = self.chats.values()
+            .filter(|chat| {
+                chat.counterparty.to_lowercase().contains(&query) ||
+                chat.counterparty_nickname.as_ref().map_or(false, |n| n.to_lowercase().contains(&query)) ||
+                chat.messages.iter().any(|m| m.content.to_lowercase().contains(&query))
+            })
+            .cloned()
+            .collect();
+
+        Ok(results)
+    }
+
+    // WEBSOCKET HANDLERS
+
+    #[ws]
+    fn websocket(&mut self, channel_id: u32, message_type: WsMessageType, blob: LazyLoadBlob) {
+        // Guests arrive on the unauthenticated /public-ws binding, the owner's UI on /ws
+        if self.public_channels.contains(&channel_id) || Self::is_public_channel(channel_id) {
+            self.public_channels.insert(channel_id);
+            self.handle_guest_ws(channel_id, message_type, blob);
+        } else {
+            self.handle_owner_ws(channel_id, message_type, blob);
+        }
+    }
+}
+
+// Helper methods implementation
+impl ChatState {
+    // Normalize chat ID to prevent duplicates
+    // Always returns the ID in alphabetical order: "nodeA:nodeB"
+    fn normalize_chat_id(node1: &str, node2: &str) -> String {
+        if node1 < node2 {
+            format!("{}:{}", node1, node2)
+        } else {
+            format!("{}:{}", node2, node1)
+        }
+    }
+
+    // Chat IDs contain ':' which we don't use in VFS paths or URLs
+    fn chat_dir(chat_id: &str) -> String {
+        chat_id.replace(":", "_")
+    }
+
+    // Attachment bytes carried in a message's file URL, either gzip-compressed or as a data URL.
+    // Undecodable data is treated as no attachment; data over `max_bytes` is refused.
+    fn decode_inbound_file(url: &str, max_bytes: u64) -> Result<Vec<u8>, String> {
+        let file_data = if let Some(compressed_b64) = url.strip_prefix("compressed:") {
+            match base64_decode(compressed_b64) {
+                Ok(compressed_data) => decompress_data(&compressed_data, max_bytes)
+                    .map_err(|e| format!("Attachment refused: {}", e))?,
+                Err(e) => {
+                    println!("Failed to decode compressed file: {}", e);
+                    Vec::new()
+                }
+            }
+        } else if url.starts_with("data:") {
+            match url.find(',').map(|comma_pos| base64_decode(&url[comma_pos + 1..])) {
+                Some(Ok(data)) => data,
+                Some(Err(e)) => {
+                    println!("Failed to decode file data: {}", e);
+                    Vec::new()
+                }
+                None => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+
+        if file_data.len() as u64 > max_bytes {
+            return Err(format!("Attachment refused: larger than {} bytes", max_bytes));
+        }
+        Ok(file_data)
+    }
+
+    // URL under which a stored file is served by serve_file
+    fn file_url(stored: &StoredFile) -> String {
+        format!("/files/{}/{}", Self::chat_dir(&stored.chat_id), stored.file_id)
+    }
+
+    // Store file bytes by content hash and record a reference to them in the file index.
+    // Identical content is only written to VFS once.
+    fn store_chat_file(
+        &mut self,
+        chat_id: &str,
+        message_id: &str,
+        sender: &str,
+        filename: &str,
+        mime_type: &str,
+        data: &[u8],
+        timestamp: u64,
+    ) -> Result<StoredFile, String> {
+        let hash = format!("{:x}", Sha256::digest(data));
+
+        if let Some(blob) = self.blobs.get_mut(&hash) {
+            blob.ref_count += 1;
+        } else {
+            let package_id = our().package_id();
+            let dir_path = format!("/{}/files/blobs", package_id);
+            let vfs_path = format!("{}/{}", dir_path, hash);
+
+            // Create directory if it doesn't exist
+            let _ = vfs::open_dir(&dir_path, true, Some(5));
+
+            let file = vfs::create_file(&vfs_path, Some(5))
+                .map_err(|e| format!("Failed to create VFS file: {:?}", e))?;
+            file.write(data)
+                .map_err(|e| format!("Failed to write to VFS: {:?}", e))?;
+
+            self.blobs.insert(hash.clone(), StoredBlob {
+                hash: hash.clone(),
+                size: data.len() as u64,
+                vfs_path,
+                ref_count: 1,
+                created_at: timestamp,
+            });
+        }
+
+        // Fall back to guessing from the filename if the sender gave us nothing useful
+        let mime_type = if mime_type.is_empty() || mime_type == "application/octet-stream" {
+            guess_mime_type(filename).to_string()
+        } else {
+            mime_type.to_string()
+        };
+
+        let stored = StoredFile {
+            file_id: format!("{}_{}", timestamp, rand::random::<u32>()),
+            chat_id: chat_id.to_string(),
+            message_id: message_id.to_string(),
+            filename: filename.to_string(),
+            mime_type,
+            size: data.len() as u64,
+            vfs_path: self.blobs[&hash].vfs_path.clone(),
+            etag: format!("\"{}\"", hash),
+            created_at: timestamp,
+            hash,
+            sender: sender.to_string(),
+        };
+
+        self.file_index.insert(stored.file_id.clone(), stored.clone());
+        Ok(stored)
+    }
+
+    // Add another reference to an already stored file, e.g. when forwarding it to another chat
+    fn link_stored_file(&mut self, source: &StoredFile, chat_id: &str, message_id: &str, timestamp: u64) -> StoredFile {
+        if let Some(blob) = self.blobs.get_mut(&source.hash) {
+            blob.ref_count += 1;
+        }
+
+        let stored = StoredFile {
+            file_id: format!("{}_{}", timestamp, rand::random::<u32>()),
+            chat_id: chat_id.to_string(),
+            message_id: message_id.to_string(),
+            sender: our().node.clone(),
+            created_at: timestamp,
+            ..source.clone()
+        };
+
+        self.file_index.insert(stored.file_id.clone(), stored.clone());
+        stored
+    }
+
+    fn stored_file_for_message(&self, chat_id: &str, message_id: &str) -> Option<&StoredFile> {
+        self.file_index.values()
+            .find(|f| f.chat_id == chat_id && f.message_id == message_id)
+    }
+
+    // Drop the file references held by a deleted message, removing content nobody references anymore
+    fn release_message_files(&mut self, chat_id: &str, message_id: &str) {
+        let file_ids: Vec<String> = self.file_index.values()
+            .filter(|f| f.chat_id == chat_id && f.message_id == message_id)
+            .map(|f| f.file_id.clone())
+            .collect();
+
+        self.release_stored_files(file_ids);
+    }
+
+    // Drop every file reference held by a chat and remove its legacy per-chat folder
+    fn release_chat_files(&mut self, chat_id: &str) {
+        let file_ids: Vec<String> = self.file_index.values()
+            .filter(|f| f.chat_id == chat_id)
+            .map(|f| f.file_id.clone())
+            .collect();
+
+        self.release_stored_files(file_ids);
+
+        let dir_path = format!("/{}/files/{}", our().package_id(), Self::chat_dir(chat_id));
+        if vfs::metadata(&dir_path, Some(5)).is_ok() {
+            // Files stored before deduplication may still sit in the folder; remove_dir needs it empty
+            match vfs::open_dir(&dir_path, false, Some(5)).and_then(|dir| dir.read()) {
+                Ok(entries) => {
+                    for entry in entries {
+                        if let Err(e) = vfs::remove_file(&entry.path, Some(5)) {
+                            println!("Failed to remove chat file {}: {:?}", entry.path, e);
+                        }
+                    }
+                }
+                Err(e) => println!("Failed to list chat folder {}: {:?}", dir_path, e),
+            }
+
+            match vfs::remove_dir(&dir_path, Some(5)) {
+                Ok(_) => println!("Removed chat folder {}", dir_path),
+                Err(e) => println!("Failed to remove chat folder {}: {:?}", dir_path, e),
+            }
+        }
+    }
+
+    fn release_stored_files(&mut self, file_ids: Vec<String>) {
+        for file_id in file_ids {
+            let stored = match self.file_index.remove(&file_id) {
+                Some(stored) => stored,
+                None => continue,
+            };
+
+            if stored.hash.is_empty() {
+                // Stored before deduplication: the file belongs to this message alone
+                let _ = vfs::remove_file(&stored.vfs_path, Some(5));
+                continue;
+            }
+
+            let remove_blob = match self.blobs.get_mut(&stored.hash) {
+                Some(blob) => {
+                    blob.ref_count = blob.ref_count.saturating_sub(1);
+                    blob.ref_count == 0
+                }
+                None => false,
+            };
+
+            if remove_blob {
+                if let Some(blob) = self.blobs.remove(&stored.hash) {
+                    match vfs::remove_file(&blob.vfs_path, Some(5)) {
+                        Ok(_) => println!("Removed unreferenced file {}", blob.vfs_path),
+                        Err(e) => println!("Failed to remove unreferenced file {}: {:?}", blob.vfs_path, e),
+                    }
+                }
+            }
+        }
+    }
+
+    // Attachment bytes we currently hold that were sent to us by this node
+    fn inbound_bytes_from(&self, sender: &str) -> u64 {
+        self.file_index.values()
+            .filter(|f| f.sender == sender)
+            .map(|f| f.size)
+            .sum()
+    }
+
+    fn media_type_category(mime_type: &str) -> &'static str {
+        if mime_type.starts_with("image/") {
+            "image"
+        } else if mime_type.starts_with("audio/") {
+            "audio"
+        } else if mime_type.starts_with("video/") {
+            "video"
+        } else if mime_type.starts_with("text/")
+            || mime_type == "application/pdf"
+            || mime_type.contains("document")
+            || mime_type.contains("sheet")
+            || mime_type.contains("presentation")
+        {
+            "document"
+        } else {
+            "other"
+        }
+    }
+
+    fn compute_storage_usage(&self) -> StorageUsage {
+        let mut chats: Vec<ChatStorageUsage> = self.chats.values()
+            .map(|chat| {
+                // Data URLs live in the message history itself rather than in VFS
+                let inline_bytes = chat.messages.iter()
+                    .filter_map(|m| m.file_info.as_ref())
+                    .filter(|f| f.url.starts_with("data:"))
+                    .map(|f| f.url.len() as u64)
+                    .sum();
+
+                ChatStorageUsage {
+                    chat_id: chat.id.clone(),
+                    counterparty: chat.counterparty.clone(),
+                    file_count: 0,
+                    file_bytes: 0,
+                    inline_bytes,
+                    message_count: chat.messages.len() as u32,
+                }
+            })
+            .collect();
+
+        let mut media_types: Vec<MediaTypeUsage> = Vec::new();
+        let mut referenced_bytes = 0;
+
+        for stored in self.file_index.values() {
+            referenced_bytes += stored.size;
+
+            if let Some(usage) = chats.iter_mut().find(|c| c.chat_id == stored.chat_id) {
+                usage.file_count += 1;
+                usage.file_bytes += stored.size;
+            }
+
+            let category = Self::media_type_category(&stored.mime_type);
+            match media_types.iter_mut().find(|m| m.media_type == category) {
+                Some(usage) => {
+                    usage.file_count += 1;
+                    usage.bytes += stored.size;
+                }
+                None => media_types.push(MediaTypeUsage {
+                    media_type: category.to_string(),
+                    file_count: 1,
+                    bytes: stored.size,
+                }),
+            }
+        }
+
+        // Files stored before deduplication aren't in `blobs` but still take up space
+        let legacy_bytes: u64 = self.file_index.values()
+            .filter(|f| f.hash.is_empty())
+            .map(|f| f.size)
+            .sum();
+
+        chats.sort_by(|a, b| (b.file_bytes + b.inline_bytes).cmp(&(a.file_bytes + a.inline_bytes)));
+        media_types.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+
+        StorageUsage {
+            disk_bytes: self.blobs.values().map(|b| b.size).sum::<u64>() + legacy_bytes,
+            referenced_bytes,
+            blob_count: self.blobs.len() as u32,
+            chats,
+            media_types,
+        }
+    }
+
+    // Apply global and per-chat retention policies.
+    // Returns how many messages were deleted or had their attachments removed.
+    fn apply_retention(&mut self) -> u32 {
+        let now = now_secs();
+
+        let mut released: Vec<(String, String)> = Vec::new(); // (chat_id, message_id)
+        let mut changed_chats: Vec<String> = Vec::new();
+        let mut affected = 0;
+
+        for chat in self.chats.values_mut() {
+            let policy = chat.retention.as_ref().unwrap_or(&self.settings.retention).clone();
+            if policy.is_unlimited() {
+                continue;
+            }
+
+            let cutoff = policy.max_age_days.map(|days| now.saturating_sub(days as u64 * 24 * 60 * 60));
+
+            // Messages beyond the last N (by timestamp) are expired too
+            let mut by_age: Vec<(u64, String)> = chat.messages.iter()
+                .map(|m| (m.timestamp, m.id.clone()))
+                .collect();
+            by_age.sort_by(|a, b| b.0.cmp(&a.0));
+            let over_limit: HashSet<String> = match policy.max_messages {
+                Some(max) => by_age.into_iter().skip(max as usize).map(|(_, id)| id).collect(),
+                None => HashSet::new(),
+            };
+
+            // Pinned and starred messages are kept regardless of the policy
+            let mut exempt: HashSet<String> = chat.pinned_messages.iter().map(|p| p.message_id.clone()).collect();
+            exempt.extend(self.starred_messages.iter()
+                .filter(|s| s.chat_id == chat.id)
+                .map(|s| s.message_id.clone()));
+            let is_expired = |m: &ChatMessage| {
+                !exempt.contains(&m.id)
+                    && (cutoff.map_or(false, |cutoff| m.timestamp < cutoff) || over_limit.contains(&m.id))
+            };
+
+            let before = affected;
+            if policy.attachments_only {
+                for message in chat.messages.iter_mut() {
+                    if message.file_info.is_some() && is_expired(message) {
+                        message.file_info = None;
+                        message.message_type = MessageType::Text;
+                        released.push((chat.id.clone(), message.id.clone()));
+                        affected += 1;
+                    }
+                }
+            } else {
+                let chat_id = chat.id.clone();
+                chat.messages.retain(|m| {
+                    if is_expired(m) {
+                        released.push((chat_id.clone(), m.id.clone()));
+                        affected += 1;
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
+
+            if affected > before {
+                changed_chats.push(chat.id.clone());
+            }
+        }
+
+        for (chat_id, message_id) in released {
+            self.release_message_files(&chat_id, &message_id);
+        }
+
+        for chat_id in changed_chats {
+            // Resuming guest tabs may still show the pruned messages
+            self.mark_history_changed(&chat_id);
+            if let Some(chat) = self.chats.get(&chat_id) {
+                let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+                self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+            }
+        }
+
+        affected
+    }
+
+    // Expiry for a new message in this chat, if disappearing messages are on
+    fn message_expiry(&self, chat_id: &str, timestamp: u64) -> Option<u64> {
+        self.chats.get(chat_id)
+            .and_then(|chat| chat.disappearing_timer)
+            .map(|seconds| timestamp.saturating_add(seconds))
+    }
+
+    // Set a chat's disappearing timer and record the change in its timeline
+    fn update_disappearing_timer(&mut self, chat_id: &str, seconds: Option<u64>, changed_at: u64, changed_by: &str) {
+        let chat = match self.chats.get_mut(chat_id) {
+            Some(chat) => chat,
+            None => return,
+        };
+
+        chat.disappearing_timer = seconds;
+        chat.disappearing_timer_updated_at = changed_at;
+
+        let who = if changed_by == our().node { "You" } else { changed_by };
+        let content = match seconds {
+            Some(seconds) => format!("{} set disappearing messages to {}", who, format_duration(seconds)),
+            None => format!("{} turned off disappearing messages", who),
+        };
+
+        chat.messages.push(ChatMessage {
+            id: format!("system_{}:{}", changed_at, rand::random::<u32>()),
+            sender: "System".to_string(),
+            content,
+            timestamp: changed_at,
+            status: MessageStatus::Delivered,
+            reply_to: None,
+            reactions: Vec::new(),
+            message_type: MessageType::System,
+            file_info: None,
+            expires_at: None,
+        });
+
+        let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+        self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+    }
+
+    // Delete expired disappearing messages, including copies still waiting in the delivery queue
+    fn remove_expired_messages(&mut self) -> u32 {
+        let now = now_secs();
+        let is_expired = |m: &ChatMessage| m.expires_at.map_or(false, |expires_at| expires_at <= now);
+
+        {
+            let mut queue = self.delivery_queue.lock().unwrap();
+            for messages in queue.values_mut() {
+                messages.retain(|m| !is_expired(m));
+            }
+            queue.retain(|_, messages| !messages.is_empty());
+        }
+
+        let mut released: Vec<(String, String)> = Vec::new();
+        for chat in self.chats.values_mut() {
+            let chat_id = chat.id.clone();
+            chat.messages.retain(|m| {
+                if is_expired(m) {
+                    released.push((chat_id.clone(), m.id.clone()));
+                    false
+                } else {
+                    true
+                }
+            });
+
+            // Disappearing messages go even if pinned
+            let messages = &chat.messages;
+            chat.pinned_messages.retain(|p| messages.iter().any(|m| m.id == p.message_id));
+        }
+
+        let mut changed_chats: Vec<String> = Vec::new();
+        for (chat_id, message_id) in &released {
+            self.forget_message(chat_id, message_id);
+            if !changed_chats.contains(chat_id) {
+                changed_chats.push(chat_id.clone());
+            }
+        }
+
+        for chat_id in changed_chats {
+            if let Some(chat) = self.chats.get(&chat_id) {
+                let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+                self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+            }
+        }
+
+        released.len() as u32
+    }
+
+    // Read the bytes behind a stored file
+    fn read_stored_file(stored: &StoredFile) -> Result<Vec<u8>, String> {
+        let file = vfs::open_file(&stored.vfs_path, false, Some(5))
+            .map_err(|e| format!("Failed to open file: {:?}", e))?;
+        file.read()
+            .map_err(|e| format!("Failed to read file: {:?}", e))
+    }
+
+    // Files we serve locally are sent to counterparties as compressed inline data
+    fn prepare_outgoing_file(&self, message: &mut ChatMessage, chat_id: &str) -> Result<(), String> {
+        if let Some(ref mut file_info) = message.file_info {
+            if file_info.url.starts_with("/files/") {
+                let stored = self.stored_file_for_message(chat_id, &message.id)
+                    .ok_or_else(|| "Stored file not found".to_string())?;
+                let compressed = compress_data(&Self::read_stored_file(stored)?)?;
+                file_info.url = format!("compressed:{}", base64_encode(&compressed));
+            }
+        }
+        Ok(())
+    }
+
+    async fn process_delivery_queue(&mut self) {
+        let queue_len = {
+            let queue = self.delivery_queue.lock().unwrap();
+            queue.len()
+        };
+        println!("Processing delivery queue with {} nodes", queue_len);
+
+        // Process queued messages for each node
+        let nodes_to_process: Vec<String> = {
+            let queue = self.delivery_queue.lock().unwrap();
+            queue.keys().cloned().collect()
+        };
+
+        for node in nodes_to_process {
+            // Get the first message for this node
+            let msg_to_send = {
+                let queue = self.delivery_queue.lock().unwrap();
+                queue.get(&node).and_then(|messages| messages.first().cloned())
+            };
+
+            if let Some(msg) = msg_to_send {
+                let target = Address::from((node.as_str(), OUR_PROCESS_ID));
+
+                // Try to send using generated RPC method
+                let msg_json = serde_json::to_value(&msg).unwrap();
+                let msg_for_rpc: CUChatMessage = serde_json::from_value(msg_json).unwrap();
+
+                match receive_message_remote_rpc(&target, msg_for_rpc.clone()).await {
+                    Ok(_) => {
+                        println!("Successfully delivered queued message {} to {}", msg.id, node);
+                        // Remove from queue if successful
+                        {
+                            let mut queue = self.delivery_queue.lock().unwrap();
+                            if let Some(node_queue) = queue.get_mut(&node) {
+                                node_queue.retain(|m| m.id != msg.id);
+                                if node_queue.is_empty() {
+                                    queue.remove(&node);
+                                }
+                            }
+                        }
+
+                        // Update message status in our chat
+                        for chat in self.chats.values_mut() {
+                            if let Some(message) = chat.messages.iter_mut().find(|m| m.id == msg.id) {
+                                message.status = safe_update_message_status(&message.status, MessageStatus::Sent);
+
+                                // Send ChatUpdate to WebSocket connections
+                                for &channel_id in self.ws_connections.keys() {
+                                    let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+                                    push_json(channel_id, &chat_update);
+                                }
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        // Don't attempt more messages to this node if we get Offline or Timeout
+                        println!("Failed to deliver queued message to {}: {:?}", node, e);
+                    }
+                }
+            }
+        }
+    }
+
+    // Send a text message right away, used by send_message and the scheduler
+    fn send_message_now(&mut self, req: SendMessageReq, message_id: String) -> Result<ChatMessage, String> {
+        let timestamp = now_secs();
+
+        let message = ChatMessage {
+            id: message_id,
+            sender: our().node.clone(),
+            content: req.content,
+            timestamp,
+            status: MessageStatus::Sending,
+            reply_to: req.reply_to,
+            reactions: Vec::new(),
+            message_type: MessageType::Text,
+            file_info: None,
+            expires_at: self.message_expiry(&req.chat_id, timestamp),
+        };
+
+        Ok(self.send_outgoing(&req.chat_id, message.clone(), message))
+    }
+
+    // Store and send a file right away, used by upload_file
+    fn upload_file_now(&mut self, req: UploadFileReq, message_id: String) -> Result<ChatMessage, String> {
+        let file_data = self.decode_upload(&req.data)?;
+
+        let timestamp = now_secs();
+
+        // Store file in VFS and record it in the file index
+        let stored = self.store_chat_file(
+            &req.chat_id,
+            &message_id,
+            &our().node,
+            &req.filename,
+            &req.mime_type,
+            &file_data,
+            timestamp,
+        )?;
+
+        self.send_stored_file(&stored, &file_data, req.reply_to, timestamp)
+    }
+
+    // Decode an uploaded file and check it against the size limit
+    fn decode_upload(&self, data: &str) -> Result<Vec<u8>, String> {
+        let file_data = base64_decode(data)
+            .map_err(|e| format!("Failed to decode base64: {}", e))?;
+
+        let file_size_mb = (file_data.len() as u64) / (1024 * 1024);
+        if file_size_mb > self.settings.max_file_size_mb {
+            return Err(format!("File size exceeds limit of {} MB", self.settings.max_file_size_mb));
+        }
+
+        Ok(file_data)
+    }
+
+    // Send a message for a file already in the file index, used by upload_file and the scheduler
+    fn send_stored_file(&mut self, stored: &StoredFile, data: &[u8], reply_to: Option<String>, timestamp: u64) -> Result<ChatMessage, String> {
+        let message_type = if stored.mime_type.starts_with("image/") {
+            MessageType::Image
+        } else {
+            MessageType::File
+        };
+
+        // Images use a data URL for easy inline display on both sides.
+        // Other files are served locally from VFS and sent to the counterparty compressed.
+        let (file_url, sent_url) = if message_type == MessageType::Image {
+            let data_url = format!("data:{};base64,{}", stored.mime_type, base64_encode(data));
+            (data_url.clone(), data_url)
+        } else {
+            let compressed = compress_data(data)?;
+            (Self::file_url(stored), format!("compressed:{}", base64_encode(&compressed)))
+        };
+
+        let message = ChatMessage {
+            id: stored.message_id.clone(),
+            sender: our().node.clone(),
+            content: stored.filename.clone(),
+            timestamp,
+            status: MessageStatus::Sending,
+            reply_to,
+            reactions: Vec::new(),
+            message_type,
+            file_info: Some(FileInfo {
+                filename: stored.filename.clone(),
+                mime_type: stored.mime_type.clone(),
+                size: data.len() as u64,
+                url: file_url,
+            }),
+            expires_at: self.message_expiry(&stored.chat_id, timestamp),
+        };
+
+        let mut msg_to_send = message.clone();
+        if let Some(ref mut file_info) = msg_to_send.file_info {
+            file_info.url = sent_url;
+        }
+
+        Ok(self.send_outgoing(&stored.chat_id, message, msg_to_send))
+    }
+
+    // Send a voice note right away, used by send_voice_note and the scheduler
+    fn send_voice_note_now(&mut self, req: SendVoiceNoteReq, message_id: String) -> Result<ChatMessage, String> {
+        let timestamp = now_secs();
+
+        // Store voice note
+        let file_url = format!("data:audio/webm;base64,{}", req.audio_data);
+
+        let file_info = FileInfo {
+            filename: format!("voice_note_{}.webm", message_id),
+            mime_type: "audio/webm".to_string(),
+            size: req.audio_data.len() as u64,
+            url: file_url,
+        };
+
+        let message = ChatMessage {
+            id: message_id,
+            sender: our().node.clone(),
+            content: format!("Voice note ({}s)", req.duration),
+            timestamp,
+            status: MessageStatus::Sending,
+            reply_to: req.reply_to,
+            reactions: Vec::new(),
+            message_type: MessageType::VoiceNote,
+            file_info: Some(file_info),
+            expires_at: self.message_expiry(&req.chat_id, timestamp),
+        };
+
+        Ok(self.send_outgoing(&req.chat_id, message.clone(), message))
+    }
+
+    // Add a new outgoing message to its chat, creating the chat if needed, then hold it for
+    // undo-send or deliver it. `to_send` is the message as the counterparty will receive it.
+    fn send_outgoing(&mut self, chat_id: &str, message: ChatMessage, to_send: ChatMessage) -> ChatMessage {
+        let release_at = self.undo_send_release_at(message.timestamp);
+
+        let chat = self.chats.entry(chat_id.to_string()).or_insert_with(|| {
+            let counterparty = chat_id.split(':').nth(1).unwrap_or("unknown").to_string();
+            Chat::new(chat_id.to_string(), counterparty, message.timestamp)
+        });
+        chat.messages.push(message.clone());
+        chat.last_activity = message.timestamp;
+
+        // Hold the message locally during the undo-send grace period
+        if let Some(release_at) = release_at {
+            return self.hold_pending_message(chat_id, to_send, release_at);
+        }
+
+        self.dispatch_message(chat_id, to_send);
+
+        // Return the message with its updated status
+        self.chats.get(chat_id)
+            .and_then(|chat| chat.messages.iter().find(|m| m.id == message.id).cloned())
+            .unwrap_or(message)
+    }
+
+    // Pin or unpin a message locally and tell the counterparty
+    fn set_message_pinned(&mut self, chat_id: String, message_id: String, pinned: bool) -> Result<Chat, String> {
+        let timestamp = now_secs();
+        let our_node = our().node.clone();
+
+        let chat = self.chats.get_mut(&chat_id)
+            .ok_or_else(|| "Chat not found".to_string())?;
+        if !chat.messages.iter().any(|m| m.id == message_id) {
+            return Err("Message not found".to_string());
+        }
+
+        if Self::apply_pin(chat, &message_id, pinned, &our_node, timestamp) {
+            let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+            let counterparty = chat.counterparty.clone();
+            self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+
+            if !chat_id.starts_with("browser:") {
+                let target = Address::from((counterparty.as_str(), OUR_PROCESS_ID));
+                spawn(async move {
+                    match receive_pin_update_remote_rpc(&target, our_node, message_id, pinned, timestamp).await {
+                        Ok(_) => println!("Shared pin update with {}", counterparty),
+                        Err(e) => println!("Failed to share pin update with {}: {:?}", counterparty, e),
+                    }
+                });
+            }
+        }
+
+        self.chats.get(&chat_id)
+            .cloned()
+            .ok_or_else(|| "Chat not found".to_string())
+    }
+
+    // Returns whether the pinned list changed
+    fn apply_pin(chat: &mut Chat, message_id: &str, pinned: bool, pinned_by: &str, timestamp: u64) -> bool {
+        if pinned {
+            if chat.is_pinned(message_id) {
+                return false;
+            }
+            chat.pinned_messages.push(PinnedMessage {
+                message_id: message_id.to_string(),
+                pinned_by: pinned_by.to_string(),
+                pinned_at: timestamp,
+            });
+            true
+        } else {
+            let before = chat.pinned_messages.len();
+            chat.pinned_messages.retain(|p| p.message_id != message_id);
+            chat.pinned_messages.len() != before
+        }
+    }
+
+    // Take one token from the node's bucket for this kind of traffic
+    fn check_rate(&mut self, node: &str, kind: InboundKind) -> Result<(), String> {
+        let now_ms = now_ms();
+        let bucket = self.rate_buckets
+            .entry((node.to_string(), kind))
+            .or_insert_with(|| RateBucket::new(kind, now_ms));
+        bucket.refill(kind, now_ms);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let retry_after_ms = bucket.retry_after_ms(kind);
+        Err(self.reject(
+            node,
+            InboundViolation::RateLimited,
+            format!("Too many {:?} requests", kind),
+            Some(retry_after_ms),
+        ))
+    }
+
+    // Count the violation and build the error returned to the sender
+    fn reject(&mut self, node: &str, violation: InboundViolation, detail: String, retry_after_ms: Option<u64>) -> String {
+        println!("Rejected inbound traffic from {}: {:?} ({})", node, violation, detail);
+        let now = now_secs();
+
+        match self.inbound_violations.iter_mut().find(|v| v.node == node && v.violation == violation) {
+            Some(entry) => {
+                entry.count += 1;
+                entry.last_seen = now;
+            }
+            None => self.inbound_violations.push(ViolationCount {
+                node: node.to_string(),
+                violation,
+                count: 1,
+                last_seen: now,
+            }),
+        }
+
+        serde_json::to_string(&InboundRejection { violation, detail, retry_after_ms }).unwrap()
+    }
+
+    // Apply the notification rules to an incoming message; None means stay silent
+    fn notification_for(&self, chat_id: &str, message: &ChatMessage, now: u64) -> NotifyDecision {
+        let chat = match self.chats.get(chat_id) {
+            Some(chat) => chat,
+            None => return NotifyDecision::Silent,
+        };
+        let rules = &self.settings.notification_rules;
+        let contact_override = rules.contact_override(&message.sender);
+        let keyword_hit = message.message_type == MessageType::Text
+            && rules.matches_keyword(&message.content);
+        let digests = rules.digest_interval_minutes > 0;
+
+        match contact_override.and_then(|o| o.enabled) {
+            Some(false) => return NotifyDecision::Silent,
+            Some(true) => {}
+            None => {
+                if !self.settings.notify_chats {
+                    return NotifyDecision::Silent;
+                }
+                // Keywords break through; otherwise muted chats are silent or digested,
+                // and low-priority chats are digested when digests are on
+                if !keyword_hit && chat.is_muted(now) {
+                    return if digests { NotifyDecision::Digest } else { NotifyDecision::Silent };
+                }
+                if !keyword_hit && digests && chat.low_priority {
+                    return NotifyDecision::Digest;
+                }
+            }
+        }
+
+        let bypass_quiet_hours = keyword_hit || contact_override.map_or(false, |o| o.bypass_quiet_hours);
+        if !bypass_quiet_hours && rules.in_quiet_hours(now) {
+            return if digests { NotifyDecision::Digest } else { NotifyDecision::Silent };
+        }
+
+        let hide_preview = contact_override
+            .and_then(|o| o.hide_preview)
+            .unwrap_or(rules.hide_preview);
+
+        NotifyDecision::Push {
+            sender_name: self.display_name(&message.sender),
+            body: notification_body(message, hide_preview),
+        }
+    }
+
+    // Push right away unless the chat already pushed within the coalescing window;
+    // held messages go out as one summary when the window closes
+    fn push_coalesced(&mut self, chat_id: &str, sender: &str, sender_name: String, body: String, now: u64) {
+        let window_secs = self.settings.notification_rules.coalesce_window_seconds as u64;
+
+        if let Some(window) = self.notification_windows.get_mut(chat_id) {
+            if now < window.opened_at + window_secs {
+                window.held += 1;
+                window.sender = sender.to_string();
+                window.sender_name = sender_name;
+                window.last_body = body;
+                return;
+            }
+        }
+
+        // The previous window expired before its flush ran; flush it here so its count isn't lost
+        if let Some(window) = self.notification_windows.remove(chat_id) {
+            self.push_held_notifications(chat_id, window);
+        }
+
+        if window_secs > 0 {
+            self.notification_windows.insert(chat_id.to_string(), NotificationWindow {
+                opened_at: now,
+                held: 0,
+                sender: sender.to_string(),
+                sender_name: sender_name.clone(),
+                last_body: String::new(),
+            });
+
+            let chat_id = chat_id.to_string();
+            spawn(async move {
+                let _ = sleep(window_secs * 1000).await;
+
+                let our_address = Address::from((our().node.as_str(), OUR_PROCESS_ID));
+                match flush_notifications_local_rpc(&our_address, chat_id.clone(), now).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => println!("Failed to flush notifications for {}: {}", chat_id, e),
+                    Err(e) => println!("Failed to flush notifications for {}: {:?}", chat_id, e),
+                }
+            });
+        }
+
+        let sender = sender.to_string();
+        let chat_id = chat_id.to_string();
+        spawn(async move {
+            send_push_notification_for_message(
+                &sender,
+                &format!("Message from {}", sender_name),
+                &body,
+                &chat_id
+            ).await;
+        });
+    }
+
+    // Summarise the pushes a closed window held back
+    fn push_held_notifications(&self, chat_id: &str, window: NotificationWindow) {
+        // Nothing was held back, or the user opened the app in the meantime
+        if window.held == 0 || !self.active_connections.is_empty() {
+            return;
+        }
+
+        let title = format!("{} new messages from {}", window.held + 1, window.sender_name);
+        let chat_id = chat_id.to_string();
+        spawn(async move {
+            send_push_notification_for_message(&window.sender, &title, &window.last_body, &chat_id).await;
+        });
+    }
+
+    // Remove the chat's attachments from VFS unless other chats still reference them,
+    // along with any stars pointing into it and its change record
+    fn clear_chat_data(&mut self, chat_id: &str) {
+        self.release_chat_files(chat_id);
+        self.history_changed_at.remove(chat_id);
+
+        let before = self.starred_messages.len();
+        self.starred_messages.retain(|s| s.chat_id != chat_id);
+        if self.starred_messages.len() != before {
+            self.broadcast_starred_update();
+        }
+    }
+
+    // A node we have no contact entry or chat with lands in the requests inbox
+    fn is_unsolicited(&self, node: &str) -> bool {
+        let chat_id = Self::normalize_chat_id(node, &our().node);
+        self.settings.message_requests
+            && !self.contacts.contains_key(node)
+            && !self.chats.contains_key(&chat_id)
+    }
+
+    // Nickname, then the name from their profile, then the node name
+    fn display_name(&self, node: &str) -> String {
+        if let Some(nickname) = self.contacts.get(node).and_then(|c| c.nickname.clone()) {
+            return nickname;
+        }
+        match self.node_profiles.get(node) {
+            Some(profile) if !profile.name.trim().is_empty() => profile.name.clone(),
+            _ => node.to_string(),
+        }
+    }
+
+    // Map a contact nickname to its node; anything else is taken as a node name
+    fn resolve_counterparty(&self, input: &str) -> Result<String, String> {
+        let input = input.trim();
+        if input.contains('.') || self.contacts.contains_key(input) {
+            return Ok(input.to_string());
+        }
+
+        let matches: Vec<&Contact> = self.contacts.values()
+            .filter(|c| c.nickname.as_ref().map_or(false, |n| n.eq_ignore_ascii_case(input)))
+            .collect();
+        match matches.as_slice() {
+            [] => Ok(input.to_string()),
+            [contact] => Ok(contact.node.clone()),
+            _ => Err(format!("Several contacts are nicknamed {}", input)),
+        }
+    }
+
+    fn sorted_contacts(&self, mut contacts: Vec<Contact>) -> Vec<Contact> {
+        contacts.sort_by(|a, b| {
+            b.favorite.cmp(&a.favorite).then_with(|| {
+                self.display_name(&a.node).to_lowercase()
+                    .cmp(&self.display_name(&b.node).to_lowercase())
+            })
+        });
+        contacts
+    }
+
+    // Copy the contact's nickname onto their chats so the UI and search see it
+    fn sync_contact_nickname(&mut self, node: &str) {
+        let nickname = self.contacts.get(node).and_then(|c| c.nickname.clone());
+        let chat_ids: Vec<String> = self.chats.values()
+            .filter(|chat| chat.counterparty == node && chat.counterparty_nickname != nickname)
+            .map(|chat| chat.id.clone())
+            .collect();
+        for chat_id in chat_ids {
+            let _ = self.update_chat(&chat_id, |chat| chat.counterparty_nickname = nickname.clone());
+        }
+    }
+
+    fn broadcast_contacts_update(&self) {
+        let contacts = self.sorted_contacts(self.contacts.values().cloned().collect());
+        let update = WsServerMessage::ContactsUpdate(contacts);
+        self.broadcast_to_all(serde_json::to_string(&update).unwrap());
+    }
+
+    fn list_chats(&self, req: &GetChatsReq) -> Vec<Chat> {
+        // Archived chats and message requests stay out of the inbox unless asked for
+        let mut chats: Vec<Chat> = self.chats.values()
+            .filter(|chat| chat.is_request == req.requests.unwrap_or(false))
+            .filter(|chat| chat.is_archived == req.archived.unwrap_or(false))
+            .filter(|chat| req.blocked.map_or(true, |blocked| chat.is_blocked == blocked))
+            .filter(|chat| !req.unread_only || chat.unread_count > 0)
+            .filter(|chat| req.label.as_ref().map_or(true, |label| chat.labels.contains(label)))
+            .cloned()
+            .collect();
+
+        match req.sort.clone().unwrap_or_default() {
+            ChatSort::LastActivity => chats.sort_by(|a, b| b.last_activity.cmp(&a.last_activity)),
+            ChatSort::UnreadCount => chats.sort_by(|a, b| {
+                b.unread_count.cmp(&a.unread_count)
+                    .then(b.last_activity.cmp(&a.last_activity))
+            }),
+            ChatSort::Name => chats.sort_by(|a, b| {
+                self.display_name(&a.counterparty).to_lowercase()
+                    .cmp(&self.display_name(&b.counterparty).to_lowercase())
+            }),
+        }
+
+        chats
+    }
+
+    fn validate_label_name(&self, name: &str, except_id: Option<&str>) -> Result<String, String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("Label name cannot be empty".to_string());
+        }
+        if name.chars().count() > 50 {
+            return Err("Label name too long".to_string());
+        }
+        let taken = self.labels.iter().any(|label| {
+            Some(label.id.as_str()) != except_id && label.name.eq_ignore_ascii_case(name)
+        });
+        if taken {
+            return Err("A label with that name already exists".to_string());
+        }
+        Ok(name.to_string())
+    }
+
+    fn add_label(&mut self, req: CreateLabelReq) -> Result<Label, String> {
+        let name = self.validate_label_name(&req.name, None)?;
+        let timestamp = now_secs();
+
+        let label = Label {
+            id: format!("label:{}:{}", timestamp, rand::random::<u32>()),
+            name,
+            color: req.color,
+            created_at: timestamp,
+        };
+        self.labels.push(label.clone());
+        self.broadcast_labels_update();
+
+        Ok(label)
+    }
+
+    fn edit_label(&mut self, req: UpdateLabelReq) -> Result<Label, String> {
+        let name = match req.name {
+            Some(ref name) => Some(self.validate_label_name(name, Some(&req.label_id))?),
+            None => None,
+        };
+
+        let label = self.labels.iter_mut()
+            .find(|label| label.id == req.label_id)
+            .ok_or_else(|| "Label not found".to_string())?;
+        if let Some(name) = name {
+            label.name = name;
+        }
+        if req.color.is_some() {
+            label.color = req.color;
+        }
+        let label = label.clone();
+        self.broadcast_labels_update();
+
+        Ok(label)
+    }
+
+    fn remove_label(&mut self, label_id: &str) -> Result<(), String> {
+        let before = self.labels.len();
+        self.labels.retain(|label| label.id != label_id);
+        if self.labels.len() == before {
+            return Err("Label not found".to_string());
+        }
+
+        let affected: Vec<String> = self.chats.values()
+            .filter(|chat| chat.labels.iter().any(|id| id == label_id))
+            .map(|chat| chat.id.clone())
+            .collect();
+        for chat_id in affected {
+            let _ = self.update_chat(&chat_id, |chat| chat.labels.retain(|id| id != label_id));
+        }
+
+        self.broadcast_labels_update();
+        Ok(())
+    }
+
+    fn apply_chat_labels(&mut self, req: SetChatLabelsReq) -> Result<Chat, String> {
+        let mut label_ids: Vec<String> = Vec::new();
+        for label_id in req.label_ids {
+            if !self.labels.iter().any(|label| label.id == label_id) {
+                return Err(format!("Label not found: {}", label_id));
+            }
+            if !label_ids.contains(&label_id) {
+                label_ids.push(label_id);
+            }
+        }
+
+        self.update_chat(&req.chat_id, |chat| chat.labels = label_ids)
+    }
+
+    fn broadcast_labels_update(&self) {
+        let update = WsServerMessage::LabelsUpdate(self.labels.clone());
+        self.broadcast_to_all(serde_json::to_string(&update).unwrap());
+    }
+
+    // Apply a local change to a chat and push it to our UI
+    fn update_chat<F: FnOnce(&mut Chat)>(&mut self, chat_id: &str, update: F) -> Result<Chat, String> {
+        let chat = self.chats.get_mut(chat_id)
+            .ok_or_else(|| "Chat not found".to_string())?;
+        update(chat);
+        let chat = chat.clone();
+
+        let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+        self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+
+        Ok(chat)
+    }
+
+    // Timed mutes lift themselves; clear them so the UI shows the chat as unmuted again
+    fn clear_expired_mutes(&mut self) {
+        let now = now_secs();
+
+        let expired: Vec<String> = self.chats.values()
+            .filter(|chat| chat.muted_until.map_or(false, |until| until <= now))
+            .map(|chat| chat.id.clone())
+            .collect();
+
+        for chat_id in expired {
+            let _ = self.update_chat(&chat_id, |chat| chat.muted_until = None);
+        }
+    }
+
+    fn is_starred(&self, chat_id: &str, message_id: &str) -> bool {
+        self.starred_messages.iter().any(|s| s.chat_id == chat_id && s.message_id == message_id)
+    }
+
+    fn unstar(&mut self, chat_id: &str, message_id: &str) {
+        let before = self.starred_messages.len();
+        self.starred_messages.retain(|s| !(s.chat_id == chat_id && s.message_id == message_id));
+        if self.starred_messages.len() != before {
+            self.broadcast_starred_update();
+        }
+    }
+
+    fn broadcast_starred_update(&self) {
+        let update = WsServerMessage::StarredUpdate(self.starred_messages.clone());
+        self.broadcast_to_all(serde_json::to_string(&update).unwrap());
+    }
+
+    // Clean up everything local that refers to a message that was just deleted
+    fn forget_message(&mut self, chat_id: &str, message_id: &str) {
+        self.release_message_files(chat_id, message_id);
+        self.unstar(chat_id, message_id);
+        self.mark_history_changed(chat_id);
+    }
+
+    // Guest tabs only receive new messages live, so a Resume after an existing message
+    // changed has to reload the whole chat
+    fn mark_history_changed(&mut self, chat_id: &str) {
+        let now = now_secs();
+        self.history_changed_at.insert(chat_id.to_string(), now);
+    }
+
+    // When a message sent now should leave the node, if undo-send is enabled
+    fn undo_send_release_at(&self, timestamp: u64) -> Option<u64> {
+        match self.settings.undo_send_seconds {
+            0 => None,
+            seconds => Some(timestamp + seconds.clamp(MIN_UNDO_SEND_SECONDS, MAX_UNDO_SEND_SECONDS) as u64),
+        }
+    }
+
+    // Mark a message just added to its chat as Pending and queue it for release.
+    // `to_send` is the message as the counterparty will receive it.
+    fn hold_pending_message(&mut self, chat_id: &str, to_send: ChatMessage, release_at: u64) -> ChatMessage {
+        let mut held = to_send.clone();
+        if let Some(chat) = self.chats.get_mut(chat_id) {
+            if let Some(msg) = chat.messages.iter_mut().find(|m| m.id == to_send.id) {
+                // Pending comes before Sending, so set it directly rather than as a transition
+                msg.status = MessageStatus::Pending;
+                held = msg.clone();
+            }
+
+            let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+            self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+        }
+
+        let now = now_secs();
+        Self::spawn_pending_release(chat_id, &to_send.id, release_at.saturating_sub(now));
+
+        self.pending_sends.push(PendingSend {
+            chat_id: chat_id.to_string(),
+            message: to_send,
+            release_at,
+        });
+
+        held
+    }
+
+    fn spawn_pending_release(chat_id: &str, message_id: &str, delay_secs: u64) {
+        let chat_id = chat_id.to_string();
+        let message_id = message_id.to_string();
+        spawn(async move {
+            let _ = sleep(delay_secs * 1000).await;
+
+            let our_address = Address::from((our().node.as_str(), OUR_PROCESS_ID));
+            match release_pending_message_local_rpc(&our_address, chat_id, message_id.clone()).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => println!("Failed to release pending message {}: {}", message_id, e),
+                Err(e) => println!("Failed to release pending message {}: {:?}", message_id, e),
+            }
+        });
+    }
+
+    // Check a guest against the link's policies, recording their device as a use of the link
+    fn admit_guest(
+        &mut self,
+        chat_key: &str,
+        device_id: Option<String>,
+        passphrase: Option<String>,
+        display_name: Option<String>,
+    ) -> Result<(), String> {
+        let now = now_secs();
+        let key_data = self.chat_keys.get_mut(chat_key)
+            .ok_or_else(|| "Invalid chat key".to_string())?;
+
+        if key_data.is_expired(now) {
+            return Err("Chat link has expired".to_string());
+        }
+        if let Some(ref expected) = key_data.passphrase_hash {
+            // Wrong guesses drain a bucket per link, so a passphrase can't be brute-forced
+            let kind = InboundKind::PassphraseAttempt;
+            let now_ms = now * 1000;
+            let attempts = self.guest_rate_buckets
+                .entry((chat_key.to_string(), kind))
+                .or_insert_with(|| RateBucket::new(kind, now_ms));
+            attempts.refill(kind, now_ms);
+            if attempts.tokens < 1.0 {
+                let wait_secs = attempts.retry_after_ms(kind).div_ceil(1000);
+                return Err(format!("Too many incorrect passphrases, try again in {} seconds", wait_secs));
+            }
+
+            let given = passphrase.unwrap_or_default();
+            if !verify_passphrase(chat_key, &given, expected) {
+                attempts.tokens -= 1.0;
+                return Err("Incorrect passphrase".to_string());
+            }
+        }
+
+        // Guests without a device id all count as the same device
+        let device_id = device_id.unwrap_or_default();
+        if !key_data.claimed_by.contains(&device_id) {
+            if let Some(max_uses) = key_data.max_uses {
+                if key_data.claimed_by.len() as u32 >= max_uses {
+                    return Err("Chat link has already been used".to_string());
+                }
+            }
+            key_data.claimed_by.push(device_id);
+        }
+
+        let new_name = display_name
+            .map(|n| truncate_chars(n.trim(), MAX_PROFILE_NAME_CHARS))
+            .filter(|n| !key_data.name_locked && !n.is_empty() && *n != key_data.user_name);
+        if let Some(name) = new_name {
+            let old_name = std::mem::replace(&mut key_data.user_name, name.clone());
+            let chat_id = key_data.chat_id.clone();
+
+            // A guest chat is named after its guest
+            let is_guest_chat = self.chats.get(&chat_id)
+                .map_or(false, |chat| chat.is_guest && chat.counterparty == old_name);
+            if is_guest_chat {
+                let _ = self.update_chat(&chat_id, |chat| chat.counterparty = name);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Push a message to every guest connected to the chat, with attachment URLs signed for each key
+    fn push_to_guests(&self, chat_id: &str, message: &ChatMessage) -> usize {
+        let mut reached = 0;
+        for session in self.guest_sessions.values().filter(|session| session.chat_id == chat_id) {
+            if self.chat_keys.get(&session.chat_key).map_or(true, |key| key.is_revoked) {
+                continue;
+            }
+            let msg = WsServerMessage::NewMessage(self.guest_view(&session.chat_key, message));
+            push_json(session.channel_id, &msg);
+            reached += 1;
+        }
+        reached
+    }
+
+    // Guests can't reach the authenticated /files/* binding, so attachments point at signed public URLs
+    fn guest_view(&self, chat_key: &str, message: &ChatMessage) -> ChatMessage {
+        let mut message = message.clone();
+        let chat_id = match self.chat_keys.get(chat_key) {
+            Some(key) => key.chat_id.clone(),
+            None => return message,
+        };
+        if let Some(ref mut file_info) = message.file_info {
+            match self.stored_file_for_message(&chat_id, &message.id) {
+                Some(stored) => file_info.url = self.guest_file_url(chat_key, &stored.file_id),
+                None => file_info.url = String::new(),
+            }
+        }
+        message
+    }
+
+    fn guest_file_mac(&self, chat_key: &str, file_id: &str, expires: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.url_signing_secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{}/{}/{}", chat_key, file_id, expires).as_bytes());
+        mac
+    }
+
+    fn sign_guest_file(&self, chat_key: &str, file_id: &str, expires: u64) -> String {
+        format!("{:x}", self.guest_file_mac(chat_key, file_id, expires).finalize().into_bytes())
+    }
+
+    // Constant-time check of a signature from a guest file URL
+    fn verify_guest_file(&self, chat_key: &str, file_id: &str, expires: u64, signature: &str) -> bool {
+        decode_hex(signature).map_or(false, |signature| {
+            self.guest_file_mac(chat_key, file_id, expires).verify_slice(&signature).is_ok()
+        })
+    }
+
+    // Expiry is rounded to the TTL so a file's URL stays the same, and cacheable, for a while
+    fn guest_file_url(&self, chat_key: &str, file_id: &str) -> String {
+        let now = now_secs();
+        let expires = (now / GUEST_FILE_URL_TTL_SECS + 2) * GUEST_FILE_URL_TTL_SECS;
+        let signature = self.sign_guest_file(chat_key, file_id, expires);
+        format!("/public/files/{}/{}/{}/{}", chat_key, file_id, expires, signature)
+    }
+
+    // The key behind an authenticated guest channel, provided its link is still valid
+    fn guest_key_for_channel(&mut self, channel_id: u32) -> Option<ChatKey> {
+        let key_data = self.guest_sessions.get(&channel_id)
+            .and_then(|session| self.chat_keys.get(&session.chat_key))
+            .cloned()?;
+
+        let now = now_secs();
+
+        // A link revoked or expired mid-session stops working right away
+        if !key_data.is_usable(now) {
+            self.guest_sessions.remove(&channel_id);
+            let msg = WsServerMessage::AuthFailed {
+                reason: "Chat link is no longer valid".to_string(),
+            };
+            push_json(channel_id, &msg);
+            return None;
+        }
+
+        Some(key_data)
+    }
+
+    // Scheme and host our public pages are reached at
+    fn public_base_url(&self) -> String {
+        match self.settings.public_base_url {
+            Some(ref url) if !url.trim().is_empty() => url.trim().trim_end_matches('/').to_string(),
+            _ => format!("http://{}", our().node),
+        }
+    }
+
+    // Register an authenticated guest tab and deliver the replies that waited for one
+    fn open_guest_session(&mut self, channel_id: u32, key_data: &ChatKey, device_id: Option<String>) {
+        let connected_at = now_secs();
+
+        // Each tab gets its own session, so a key can be open in several at once
+        self.guest_sessions.insert(channel_id, GuestSession {
+            channel_id,
+            chat_key: key_data.key.clone(),
+            chat_id: key_data.chat_id.clone(),
+            display_name: key_data.user_name.clone(),
+            device_id,
+            connected_at,
+            last_message: None,
+            last_message_at: None,
+            message_count: 0,
+        });
+
+        let is_guest = self.chats.get(&key_data.chat_id).map_or(false, |chat| chat.is_guest);
+        if is_guest {
+            let our_node = our().node.clone();
+            let _ = self.update_chat(&key_data.chat_id, |chat| {
+                for msg in chat.messages.iter_mut()
+                    .filter(|m| m.sender == our_node && m.status == MessageStatus::Sent) {
+                    msg.status = safe_update_message_status(&msg.status, MessageStatus::Delivered);
+                }
+            });
+        }
+    }
+
+    // One token per device and link; re-authenticating replaces the old one
+    fn issue_resume_token(&mut self, chat_key: &str, device_id: Option<String>) -> String {
+        let issued_at = now_secs();
+        self.resume_tokens.retain(|_, token| {
+            !(token.chat_key == chat_key && token.device_id == device_id)
+                && issued_at < token.issued_at + GUEST_RESUME_TOKEN_TTL_SECS
+        });
+
+        let token = format!("{:x}{:x}", rand::random::<u128>(), rand::random::<u128>());
+        self.resume_tokens.insert(token.clone(), GuestResumeToken {
+            chat_key: chat_key.to_string(),
+            device_id,
+            issued_at,
+            synced_at: issued_at,
+        });
+        token
+    }
+
+    // A fresh single-device link and chat for a website visitor
+    fn provision_widget_key(&mut self, ticket: &str, device_id: Option<String>, name: Option<String>, email: Option<String>) -> Result<String, String> {
+        if !self.settings.widget.enabled {
+            return Err("The chat widget is disabled".to_string());
+        }
+
+        let timestamp = now_secs();
+        if !self.verify_widget_ticket(ticket, timestamp) {
+            return Err("This chat window has expired, please reload the page".to_string());
+        }
+
+        let name = name
+            .map(|n| truncate_chars(n.trim(), MAX_PROFILE_NAME_CHARS))
+            .filter(|n| !n.is_empty());
+        let email = email
+            .map(|e| e.trim().to_string())
+            .filter(|e| !e.is_empty());
+        if self.settings.widget.ask_name && name.is_none() {
+            return Err("Please enter your name".to_string());
+        }
+        if let Some(ref email) = email {
+            if email.len() > 254 || !email.contains('@') || email.chars().any(char::is_whitespace) {
+                return Err("Invalid email address".to_string());
+            }
+        } else if self.settings.widget.ask_email {
+            return Err("Please enter your email address".to_string());
+        }
+
+        // Tickets are free to fetch, so new chats are limited across all widget pages together
+        let kind = InboundKind::WidgetStart;
+        let now_ms = timestamp * 1000;
+        let bucket = self.guest_rate_buckets
+            .entry(("widget".to_string(), kind))
+            .or_insert_with(|| RateBucket::new(kind, now_ms));
+        bucket.refill(kind, now_ms);
+        if bucket.tokens < 1.0 {
+            return Err("Too many new chats right now, please try again later".to_string());
+        }
+        bucket.tokens -= 1.0;
+
+        let key = format!("{:x}", rand::random::<u128>());
+
+        self.chat_keys.insert(key.clone(), ChatKey {
+            key: key.clone(),
+            user_name: name.clone()
+                .unwrap_or_else(|| format!("Visitor-{}", rand::random::<u32>() % 10000)),
+            created_at: timestamp,
+            is_revoked: false,
+            chat_id: format!("widget:{}", key),
+            expires_at: None,
+            max_uses: Some(1),
+            claimed_by: device_id.into_iter().collect(),
+            passphrase_hash: None,
+            name_locked: name.is_some(),
+            uploaded_bytes: 0,
+            is_banned: false,
+            is_widget: true,
+            visitor_email: email,
+            away_replied_at: None,
+            upgraded_to: None,
+        });
+
+        // Links nobody has written on are cheap to make, so older ones give way to this one
+        self.remove_idle_widget_keys();
+
+        Ok(key)
+    }
+
+    // Widget pages carry a signed ticket that WidgetStart has to present. The guest socket
+    // can't see the embedding site's origin (the widget frame opens it from ours), but only
+    // allowed origins can frame the page, and other sites' scripts can't read it.
+    fn widget_ticket(&self, expires: u64) -> String {
+        format!("{}.{:x}", expires, self.widget_ticket_mac(expires).finalize().into_bytes())
+    }
+
+    fn widget_ticket_mac(&self, expires: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.url_signing_secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("widget/{}", expires).as_bytes());
+        mac
+    }
+
+    fn verify_widget_ticket(&self, ticket: &str, now: u64) -> bool {
+        let (expires, signature) = match ticket.split_once('.') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let expires: u64 = match expires.parse() {
+            Ok(expires) => expires,
+            Err(_) => return false,
+        };
+        now < expires && decode_hex(signature).map_or(false, |signature| {
+            self.widget_ticket_mac(expires).verify_slice(&signature).is_ok()
+        })
+    }
+
+    // Drop widget links whose visitor never wrote anything once they've been idle for
+    // WIDGET_KEY_IDLE_SECS. Tabs still connected keep their link, but only the newest
+    // MAX_CONNECTED_PENDING_WIDGET_KEYS of them; beyond MAX_PENDING_WIDGET_KEYS the oldest
+    // unconnected links go first, so open sockets alone can't use up the room for new visitors.
+    fn remove_idle_widget_keys(&mut self) {
+        let now = now_secs();
+
+        let connected: HashSet<&str> = self.guest_sessions.values()
+            .map(|session| session.chat_key.as_str())
+            .collect();
+        let mut pending: Vec<(u64, String)> = self.chat_keys.values()
+            .filter(|key| key.is_widget && !self.chats.contains_key(&key.chat_id))
+            .map(|key| (key.created_at, key.key.clone()))
+            .collect();
+        pending.sort();
+        let (pending_connected, pending_unconnected): (Vec<_>, Vec<_>) = pending.into_iter()
+            .partition(|(_, key)| connected.contains(key.as_str()));
+
+        let over_connected = pending_connected.len().saturating_sub(MAX_CONNECTED_PENDING_WIDGET_KEYS);
+        let crowded_out: HashSet<String> = pending_connected.into_iter()
+            .take(over_connected)
+            .map(|(_, key)| key)
+            .collect();
+        let room = MAX_PENDING_WIDGET_KEYS.saturating_sub(MAX_CONNECTED_PENDING_WIDGET_KEYS);
+        let over_cap = pending_unconnected.len().saturating_sub(room);
+        let mut idle: HashSet<String> = pending_unconnected.into_iter()
+            .enumerate()
+            .filter(|(i, (created_at, _))| *i < over_cap || now >= created_at + WIDGET_KEY_IDLE_SECS)
+            .map(|(_, (_, key))| key)
+            .collect();
+
+        let channels: Vec<u32> = self.guest_sessions.values()
+            .filter(|session| crowded_out.contains(&session.chat_key))
+            .map(|session| session.channel_id)
+            .collect();
+        for channel_id in channels {
+            self.disconnect_guest(channel_id, "This chat window was closed to make room, please reload the page");
+        }
+        idle.extend(crowded_out);
+
+        if !idle.is_empty() {
+            println!("Removing {} unused widget links", idle.len());
+            self.chat_keys.retain(|key, _| !idle.contains(key));
+            self.resume_tokens.retain(|_, token| !idle.contains(&token.chat_key));
+        }
+
+        // Buckets for removed links have nothing left to limit
+        self.guest_rate_buckets.retain(|(key, _), _| !idle.contains(key));
+    }
+
+    // Outside office hours, let the visitor know, at most once per WIDGET_AWAY_REPLY_INTERVAL_SECS
+    fn send_away_reply(&mut self, chat_key: &str, now: u64) {
+        let widget = &self.settings.widget;
+        let away_message = match widget.away_message.as_deref().map(str::trim) {
+            Some(message) if !message.is_empty() && !widget.is_open(now) => message.to_string(),
+            _ => return,
+        };
+        let chat_id = match self.chat_keys.get_mut(chat_key) {
+            Some(key) if key.is_widget => {
+                if key.away_replied_at.map_or(false, |at| now < at + WIDGET_AWAY_REPLY_INTERVAL_SECS) {
+                    return;
+                }
+                key.away_replied_at = Some(now);
+                key.chat_id.clone()
+            }
+            _ => return,
+        };
+
+        let message = ChatMessage {
+            id: format!("{}:{}", now, rand::random::<u32>()),
+            sender: our().node.clone(),
+            content: away_message,
+            timestamp: now,
+            status: MessageStatus::Sending,
+            reply_to: None,
+            reactions: Vec::new(),
+            message_type: MessageType::Text,
+            file_info: None,
+            expires_at: self.message_expiry(&chat_id, now),
+        };
+        match self.chats.get_mut(&chat_id) {
+            Some(chat) => chat.messages.push(message.clone()),
+            None => return,
+        }
+
+        let new_message = WsServerMessage::NewMessage(message.clone());
+        self.broadcast_to_all(serde_json::to_string(&new_message).unwrap());
+        self.deliver_to_guests(&chat_id, &message.id);
+    }
+
+    // Issue the challenge a guest's node must send back to prove the guest controls it
+    fn start_guest_upgrade(&mut self, key_data: &ChatKey, node: &str) -> Result<WsServerMessage, String> {
+        let node = node.trim().to_lowercase();
+        if node.is_empty() || !node.contains('.') || node.chars().any(char::is_whitespace) {
+            return Err("Enter the name of your node, e.g. alice.os".to_string());
+        }
+        if node == our().node {
+            return Err("That's the host's own node".to_string());
+        }
+
+        let now = now_secs();
+        // One outstanding challenge per link
+        self.guest_upgrades.retain(|_, upgrade| {
+            upgrade.chat_key != key_data.key && now < upgrade.created_at + GUEST_UPGRADE_TTL_SECS
+        });
+
+        let challenge = format!("{:x}", rand::random::<u128>());
+        self.guest_upgrades.insert(challenge.clone(), GuestUpgrade {
+            chat_key: key_data.key.clone(),
+            node: node.clone(),
+            created_at: now,
+        });
+
+        Ok(WsServerMessage::UpgradeChallenge {
+            host: our().node.clone(),
+            node,
+            challenge,
+            expires_at: now + GUEST_UPGRADE_TTL_SECS,
+        })
+    }
+
+    // Fold a guest chat into our 1:1 chat with `node` and retire the link it came through
+    fn merge_guest_chat(&mut self, key_data: &ChatKey, node: &str) -> Chat {
+        let our_node = our().node.clone();
+        let from_id = key_data.chat_id.clone();
+        let into_id = Self::normalize_chat_id(&our_node, node);
+        let timestamp = now_secs();
+
+        let guest_chat = self.chats.remove(&from_id);
+        let pinned = guest_chat.as_ref().map(|chat| chat.pinned_messages.clone()).unwrap_or_default();
+        let labels = guest_chat.as_ref().map(|chat| chat.labels.clone()).unwrap_or_default();
+        let mut messages = guest_chat.map(|chat| chat.messages).unwrap_or_default();
+
+        // Whatever the guest wrote was written by the node's owner; timeline notices stay as they are
+        for message in messages.iter_mut()
+            .filter(|m| m.sender != our_node && m.message_type != MessageType::System) {
+            message.sender = node.to_string();
+        }
+
+        // Files stored before deduplication sit in the guest chat's own folder, which goes away
+        // with the guest chat, so they move into the shared blob store first
+        let legacy: Vec<StoredFile> = self.file_index.values()
+            .filter(|stored| stored.chat_id == from_id && stored.hash.is_empty())
+            .cloned()
+            .collect();
+        let mut legacy_left = false;
+        for stored in legacy {
+            let moved = Self::read_stored_file(&stored).and_then(|data| self.store_chat_file(
+                &into_id,
+                &stored.message_id,
+                &stored.sender,
+                &stored.filename,
+                &stored.mime_type,
+                &data,
+                stored.created_at,
+            ));
+            match moved {
+                Ok(_) => self.release_stored_files(vec![stored.file_id]),
+                Err(e) => {
+                    println!("Failed to move chat file {}: {}", stored.vfs_path, e);
+                    legacy_left = true;
+                }
+            }
+        }
+
+        // Attachments and stars move along with their messages
+        for stored in self.file_index.values_mut().filter(|stored| stored.chat_id == from_id) {
+            stored.chat_id = into_id.clone();
+        }
+        if !legacy_left {
+            // Nothing in the index points at the guest chat any more, so this only removes its folder
+            self.release_chat_files(&from_id);
+        }
+        for message in messages.iter_mut() {
+            if let Some(ref mut file_info) = message.file_info {
+                if let Some(stored) = self.stored_file_for_message(&into_id, &message.id) {
+                    file_info.url = Self::file_url(stored);
+                }
+            }
+        }
+        let mut stars_moved = false;
+        for star in self.starred_messages.iter_mut().filter(|s| s.chat_id == from_id) {
+            star.chat_id = into_id.clone();
+            stars_moved = true;
+        }
+
+        let chat = self.chats.entry(into_id.clone()).or_insert_with(|| Chat {
+            counterparty_profile: self.node_profiles.get(node).cloned(),
+            counterparty_nickname: self.contacts.get(node).and_then(|c| c.nickname.clone()),
+            ..Chat::new(into_id.clone(), node.to_string(), timestamp)
+        });
+
+        let known: HashSet<String> = chat.messages.iter().map(|m| m.id.clone()).collect();
+        chat.messages.extend(messages.into_iter().filter(|m| !known.contains(&m.id)));
+        chat.messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        if let Some(last) = chat.messages.last() {
+            chat.last_activity = last.timestamp;
+        }
+        for message_id in pinned {
+            if !chat.pinned_messages.contains(&message_id) {
+                chat.pinned_messages.push(message_id);
+            }
+        }
+        for label in labels {
+            if !chat.labels.contains(&label) {
+                chat.labels.push(label);
+            }
+        }
+        // The guest is now a known node, not a stranger
+        chat.is_request = false;
+        let chat = chat.clone();
+
+        if let Some(key) = self.chat_keys.get_mut(&key_data.key) {
+            key.is_revoked = true;
+            key.upgraded_to = Some(node.to_string());
+        }
+        self.resume_tokens.retain(|_, token| token.chat_key != key_data.key);
+
+        let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+        self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+        let merged = WsServerMessage::ChatMerged { from_chat_id: from_id, into_chat_id: into_id.clone() };
+        self.broadcast_to_all(serde_json::to_string(&merged).unwrap());
+        if stars_moved {
+            self.broadcast_starred_update();
+        }
+
+        // Open guest tabs learn where the conversation went, then close
+        let channels: Vec<u32> = self.guest_sessions.values()
+            .filter(|session| session.chat_key == key_data.key)
+            .map(|session| session.channel_id)
+            .collect();
+        for channel_id in channels {
+            let msg = WsServerMessage::Upgraded { node: node.to_string(), chat_id: into_id.clone() };
+            push_json(channel_id, &msg);
+            self.disconnect_guest(channel_id, &format!("This conversation continues on {}", node));
+        }
+
+        chat
+    }
+
+    // Add history fetched from the host to our 1:1 chat with them
+    fn import_upgraded_chat(&mut self, host: &str, history: Vec<ChatMessage>) -> Chat {
+        let chat_id = Self::normalize_chat_id(&our().node, host);
+        let timestamp = now_secs();
+
+        let chat = self.chats.entry(chat_id.clone()).or_insert_with(|| Chat {
+            counterparty_profile: self.node_profiles.get(host).cloned(),
+            counterparty_nickname: self.contacts.get(host).and_then(|c| c.nickname.clone()),
+            ..Chat::new(chat_id.clone(), host.to_string(), timestamp)
+        });
+
+        // Attachment URLs point at the host's files, which we can't serve, so keep the text only
+        let known: HashSet<String> = chat.messages.iter().map(|m| m.id.clone()).collect();
+        for mut message in history.into_iter().filter(|m| !known.contains(&m.id)) {
+            if let Some(ref mut file_info) = message.file_info {
+                file_info.url = String::new();
+            }
+            message.status = MessageStatus::Delivered;
+            chat.messages.push(message);
+        }
+        chat.messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        if let Some(last) = chat.messages.last() {
+            chat.last_activity = last.timestamp;
+        }
+        chat.is_request = false;
+        let chat = chat.clone();
+
+        let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+        self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+        chat
+    }
+
+    // Guest messages are limited per key, so opening more tabs doesn't buy more throughput
+    // Guests get their own buckets: the chat key is a secret, so it must never reach the
+    // logs or the node violation report that check_rate feeds
+    fn check_guest_rate(&mut self, channel_id: u32, key_data: &ChatKey) -> bool {
+        let now_ms = now_ms();
+        let kind = InboundKind::GuestMessage;
+        let bucket = self.guest_rate_buckets
+            .entry((key_data.key.clone(), kind))
+            .or_insert_with(|| RateBucket::new(kind, now_ms));
+        bucket.refill(kind, now_ms);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+
+        let error = WsServerMessage::Error {
+            message: "You're sending messages too quickly. Please wait a moment.".to_string(),
+        };
+        push_json(channel_id, &error);
+        false
+    }
+
+    fn record_guest_activity(&mut self, channel_id: u32, message: &ChatMessage) {
+        if let Some(session) = self.guest_sessions.get_mut(&channel_id) {
+            session.last_message = Some(truncate_chars(&message.content, 100));
+            session.last_message_at = Some(message.timestamp);
+            session.message_count += 1;
+        }
+    }
+
+    // Tell a guest tab why it's being dropped, then close its socket
+    fn disconnect_guest(&mut self, channel_id: u32, reason: &str) {
+        self.guest_sessions.remove(&channel_id);
+        self.public_channels.remove(&channel_id);
+        let msg = WsServerMessage::Kicked { reason: reason.to_string() };
+        push_json(channel_id, &msg);
+        send_ws_push(channel_id, WsMessageType::Close, LazyLoadBlob {
+            mime: None,
+            bytes: Vec::new(),
+        });
+    }
+
+    // Store a guest's upload, enforcing the owner's file size limit and the link's quota
+    fn store_guest_file(&mut self, key_data: &ChatKey, filename: &str, mime_type: &str, data: &str) -> Result<ChatMessage, String> {
+        let file_data = base64_decode(data)
+            .map_err(|e| format!("Failed to decode base64: {}", e))?;
+
+        let max_size = self.settings.max_file_size_mb * 1024 * 1024;
+        if file_data.len() as u64 > max_size {
+            return Err(format!("File exceeds the {}MB size limit", self.settings.max_file_size_mb));
+        }
+        let quota = self.settings.guest_upload_quota_mb * 1024 * 1024;
+        if quota > 0 && key_data.uploaded_bytes + file_data.len() as u64 > quota {
+            return Err("Upload quota for this chat link exceeded".to_string());
+        }
+
+        let timestamp = now_secs();
+        let message_id = format!("{}:{}", timestamp, rand::random::<u32>());
+        let filename = truncate_chars(filename.trim(), 255);
+
+        let stored = self.store_chat_file(
+            &key_data.chat_id,
+            &message_id,
+            &key_data.user_name,
+            &filename,
+            mime_type,
+            &file_data,
+            timestamp,
+        )?;
+        if let Some(key) = self.chat_keys.get_mut(&key_data.key) {
+            key.uploaded_bytes += stored.size;
+        }
+
+        let message_type = if stored.mime_type.starts_with("image/") {
+            MessageType::Image
+        } else {
+            MessageType::File
+        };
+
+        Ok(ChatMessage {
+            id: message_id,
+            sender: key_data.user_name.clone(),
+            content: filename.clone(),
+            timestamp,
+            status: MessageStatus::Delivered,
+            reply_to: None,
+            reactions: Vec::new(),
+            message_type,
+            file_info: Some(FileInfo {
+                filename,
+                mime_type: stored.mime_type.clone(),
+                size: stored.size,
+                url: Self::file_url(&stored),
+            }),
+            expires_at: self.message_expiry(&key_data.chat_id, timestamp),
+        })
+    }
+
+    // Add a guest's message to their chat and fan it out to the other guest tabs and the owner
+    fn accept_guest_message(&mut self, key_data: &ChatKey, message: ChatMessage) {
+        let timestamp = message.timestamp;
+        let is_new_chat = !self.chats.contains_key(&key_data.chat_id);
+        let chat = self.chats.entry(key_data.chat_id.clone())
+            .or_insert_with(|| Chat::new(key_data.chat_id.clone(), key_data.user_name.clone(), timestamp));
+
+        // A link can point at a chat that already existed; once a guest writes there,
+        // our replies have to go to the guest rather than over P2P
+        chat.is_guest = true;
+        chat.messages.push(message.clone());
+        chat.last_activity = timestamp;
+        chat.unread_count += 1;
+
+        // Every guest tab on this chat sees the message, including the sender's
+        self.push_to_guests(&key_data.chat_id, &message);
+
+        // And so does the owner's UI
+        if is_new_chat {
+            if let Some(chat) = self.chats.get(&key_data.chat_id) {
+                let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+                self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+            }
+        }
+        let new_message = WsServerMessage::NewMessage(message.clone());
+        self.broadcast_to_all(serde_json::to_string(&new_message).unwrap());
+
+        if key_data.is_widget {
+            self.send_away_reply(&key_data.key, timestamp);
+        }
+
+        if self.active_connections.is_empty() {
+            match self.notification_for(&key_data.chat_id, &message, timestamp) {
+                NotifyDecision::Push { sender_name, body } => {
+                    self.push_coalesced(&key_data.chat_id, &message.sender, sender_name, body, timestamp);
+                }
+                NotifyDecision::Digest => {
+                    *self.notification_digest.entry(key_data.chat_id.clone()).or_insert(0) += 1;
+                }
+                NotifyDecision::Silent => {}
+            }
+        }
+    }
+
+    // Push one of our messages to the chat's guests. Returns true for guest chats, which
+    // have no P2P leg: reaching a guest tab is what counts as delivery there, and the
+    // message otherwise waits as Sent until a guest connects.
+    fn deliver_to_guests(&mut self, chat_id: &str, message_id: &str) -> bool {
+        let (message, is_guest) = match self.chats.get(chat_id) {
+            Some(chat) => match chat.messages.iter().find(|m| m.id == message_id) {
+                Some(message) => (message.clone(), chat.is_guest),
+                None => return false,
+            },
+            None => return false,
+        };
+
+        let reached = self.push_to_guests(chat_id, &message);
+        if is_guest {
+            let _ = self.update_chat(chat_id, |chat| {
+                if let Some(msg) = chat.messages.iter_mut().find(|m| m.id == message_id) {
+                    msg.status = safe_update_message_status(&msg.status, MessageStatus::Sent);
+                    if reached > 0 {
+                        msg.status = safe_update_message_status(&msg.status, MessageStatus::Delivered);
+                    }
+                }
+            });
+        }
+
+        is_guest
+    }
+
+    // Mark a message Sent and deliver it over P2P, queueing it if the counterparty is unreachable
+    fn dispatch_message(&mut self, chat_id: &str, msg_to_send: ChatMessage) {
+        let counterparty = match self.chats.get_mut(chat_id) {
+            Some(chat) => {
+                if let Some(msg) = chat.messages.iter_mut().find(|m| m.id == msg_to_send.id) {
+                    msg.status = safe_update_message_status(&msg.status, MessageStatus::Sent);
+                }
+
+                let chat_update = WsServerMessage::ChatUpdate(chat.clone());
+                let counterparty = chat.counterparty.clone();
+                self.broadcast_to_all(serde_json::to_string(&chat_update).unwrap());
+                counterparty
+            }
+            None => return,
+        };
+
+        if self.deliver_to_guests(chat_id, &msg_to_send.id) {
+            return;
+        }
+
+        let delivery_queue = self.delivery_queue.clone();
+        let target = Address::from((counterparty.as_str(), OUR_PROCESS_ID));
+
+        spawn(async move {
+            let msg_json = serde_json::to_value(&msg_to_send).unwrap();
+            let msg_for_rpc: CUChatMessage = serde_json::from_value(msg_json).unwrap();
+            match receive_message_remote_rpc(&target, msg_for_rpc).await {
+                Ok(_) => {
+                    println!("Message {} sent successfully to {}", msg_to_send.id, counterparty);
+                }
+                Err(_) => {
+                    println!("Failed to send message {} to {}, adding to delivery queue", msg_to_send.id, counterparty);
+                    let mut queue = delivery_queue.lock().unwrap();
+                    queue.entry(counterparty.clone())
+                        .or_insert_with(Vec::new)
+                        .push(msg_to_send);
                 }
             }
-        }
-
-        Ok(())
+        });
     }
 
-    // PUBLIC BROWSER CHAT ENDPOINTS
+    // Add a message to the scheduler, returning a preview of the message it will become
+    fn schedule_message(&mut self, scheduled: ScheduledMessage) -> ChatMessage {
+        println!("Scheduled message {} for chat {} at {}", scheduled.id, scheduled.chat_id, scheduled.send_at);
 
-    #[http(path = "/public")]
-    async fn serve_public_chat(&self) -> Result<String, String> {
-        // Serve the browser chat HTML
-        Ok(include_str!("../../ui/public/browser-chat.html").to_string())
-    }
+        let preview = scheduled.preview();
+        self.scheduled_messages.push(scheduled);
+        self.scheduled_messages.sort_by_key(|m| m.send_at);
+        self.broadcast_scheduled_update();
 
-    #[http(path = "/public/join-*")]
-    async fn serve_join_link(&self) -> Result<String, String> {
-        // Serve the browser chat HTML for join links
-        Ok(include_str!("../../ui/public/browser-chat.html").to_string())
+        preview
     }
 
-    #[http(path = "/files/*")]
-    async fn serve_file(&self, path_segments: Vec<String>) -> Result<(String, Vec<u8>), String> {
-        // Extract path from segments (should be /files/chat_id/file_id)
-        if path_segments.len() < 3 {
-            return Err("Invalid file path".to_string());
-        }
-
-        let chat_id = &path_segments[1];
-        let file_id = &path_segments[2];
-
-        // Build VFS path
-        let package_id = our().package_id();
-        let vfs_path = format!("/{}/files/{}/{}", package_id, chat_id, file_id);
-
-        // Read file from VFS
-        let file = vfs::open_file(&vfs_path, false, Some(5))
-            .map_err(|e| format!("Failed to open file: {:?}", e))?;
+    fn broadcast_scheduled_update(&self) {
+        let update = WsServerMessage::ScheduledMessagesUpdate(self.scheduled_messages.clone());
+        self.broadcast_to_all(serde_json::to_string(&update).unwrap());
+    }
 
-        let file_data = file.read()
-            .map_err(|e| format!("Failed to read file: {:?}", e))?;
+    // Send every scheduled message that is due through the normal send path
+    async fn release_due_messages(&mut self) -> u32 {
+        let now = now_secs();
 
-        // Try to determine MIME type from file content or default to application/octet-stream
-        let mime_type = "application/octet-stream".to_string();
+        let (due, pending): (Vec<ScheduledMessage>, Vec<ScheduledMessage>) = self.scheduled_messages
+            .drain(..)
+            .partition(|m| m.send_at <= now);
+        self.scheduled_messages = pending;
 
-        Ok((mime_type, file_data))
-    }
-    // SEARCH
+        if due.is_empty() {
+            return 0;
+        }
 
-    #[http]
-    async fn search_chats(&self, req: SearchChatsReq) -> Result<Vec<Chat>, String> {
+        let mut sent = 0;
+        for scheduled in due {
+            let id = scheduled.id.clone();
+            let chat_id = scheduled.chat_id.clone();
+
+            let result = match scheduled.message_type {
+                MessageType::Image | MessageType::File if scheduled.file_id.is_some() => {
+                    let stored = scheduled.file_id.as_ref()
+                        .and_then(|file_id| self.file_index.get(file_id))
+                        .cloned();
+                    match stored {
+                        Some(stored) => match Self::read_stored_file(&stored) {
+                            Ok(data) => self.send_stored_file(&stored, &data, scheduled.reply_to, now),
+                            Err(e) => Err(e),
+                        },
+                        None => Err("Stored file not found".to_string()),
+                    }
+                }
+                // Scheduled before files were stored up front, so the data is still inline
+                MessageType::Image | MessageType::File => {
+                    let req = UploadFileReq {
+                        chat_id: scheduled.chat_id,
+                        filename: scheduled.filename.unwrap_or(scheduled.content),
+                        mime_type: scheduled.mime_type.unwrap_or_else(|| "application/octet-stream".to_string()),
+                        data: scheduled.data.unwrap_or_default(),
+                        reply_to: scheduled.reply_to,
+                        send_at: None,
+                    };
+                    self.upload_file_now(req, id.clone())
+                }
+                MessageType::VoiceNote => {
+                    let req = SendVoiceNoteReq {
+                        chat_id: scheduled.chat_id,
+                        audio_data: scheduled.data.unwrap_or_default(),
+                        duration: scheduled.duration.unwrap_or(0),
+                        reply_to: scheduled.reply_to,
+                        send_at: None,
+                    };
+                    self.send_voice_note_now(req, id.clone())
+                }
+                _ => {
+                    let req = SendMessageReq {
+                        chat_id: scheduled.chat_id,
+                        content: scheduled.content,
+                        reply_to: scheduled.reply_to,
+                        file_info: None,
+                        send_at: None,
+                    };
+                    self.send_message_now(req, id.clone())
+                }
+            };
 
-        let query = req.query.to_lowercase();
-        let results: Vec<Chat> = self.chats.values()
-            .filter(|chat| {
-                chat.counterparty.to_lowercase().contains(&query) ||
-                chat.messages.iter().any(|m| m.content.to_lowercase().contains(&query))
-            })
-            .cloned()
-            .collect();
+            let event = match result {
+                Ok(message) => {
+                    println!("Released scheduled message {} in chat {}", id, chat_id);
+                    sent += 1;
+                    WsServerMessage::ScheduledMessageSent { chat_id, message }
+                }
+                Err(error) => {
+                    println!("Failed to release scheduled message {}: {}", id, error);
+                    WsServerMessage::ScheduledMessageFailed { id, chat_id, error }
+                }
+            };
+            self.broadcast_to_all(serde_json::to_string(&event).unwrap());
+        }
 
-        Ok(results)
+        self.broadcast_scheduled_update();
+        sent
     }
 
-    // WEBSOCKET HANDLERS
-
-    #[ws]
-    fn websocket(&mut self, channel_id: u32, message_type: WsMessageType, blob: LazyLoadBlob) {
-        // We'll differentiate between public and private connections via authentication
+    fn handle_owner_ws(&mut self, channel_id: u32, message_type: WsMessageType, blob: LazyLoadBlob) {
         match message_type {
             WsMessageType::Close => {
                 println!("WebSocket connection closed: {}", channel_id);
@@ -2089,8 +6361,6 @@ impl ChatState {
                     self.broadcast_to_all(serde_json::to_string(&status_msg).unwrap());
                 }
 
-                // Clean up browser connections
-                self.browser_connections.retain(|_, &mut v| v != channel_id);
                 self.active_connections.remove(&channel_id);
             }
             WsMessageType::Text => {
@@ -2100,7 +6370,7 @@ impl ChatState {
                         Ok(msg) => {
                             println!("WebSocket: Received message from channel {}: {:?}", channel_id, msg);
                             // Initialize connection if not already present
-                            if !self.ws_connections.contains_key(&channel_id) && !self.browser_connections.values().any(|&ch| ch == channel_id) {
+                            if !self.ws_connections.contains_key(&channel_id) {
                                 println!("WebSocket: New connection from channel {}, initializing...", channel_id);
                                 self.ws_connections.insert(channel_id, our().node.clone());
 
@@ -2109,33 +6379,18 @@ impl ChatState {
                                 for chat in self.chats.values() {
                                     println!("WebSocket: Sending chat {} with {} messages", chat.id, chat.messages.len());
                                     let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                                    send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                                        mime: Some("application/json".to_string()),
-                                        bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                                    });
+                                    push_json(channel_id, &chat_update);
                                 }
                                 println!("WebSocket: Initial chat sync complete for channel {}", channel_id);
                             }
 
-                            // Check if this is a browser chat authentication
-                            if let WsClientMessage::AuthWithKey { .. } = &msg {
-                                self.handle_browser_message(channel_id, msg);
-                            } else if self.browser_connections.values().any(|&ch| ch == channel_id) {
-                                // If already authenticated as browser
-                                self.handle_browser_message(channel_id, msg);
-                            } else {
-                                // Node-to-node message
-                                self.handle_client_message(channel_id, msg);
-                            }
+                            self.handle_client_message(channel_id, msg);
                         }
                         Err(e) => {
                             let error = WsServerMessage::Error {
                                 message: format!("Invalid message format: {}", e),
                             };
-                            send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                            mime: Some("application/json".to_string()),
-                            bytes: serde_json::to_string(&error).unwrap().into_bytes(),
-                        });
+                            push_json(channel_id, &error);
                         }
                     }
                 }
@@ -2149,84 +6404,52 @@ impl ChatState {
             }
         }
     }
-}
 
-// Helper methods implementation
-impl ChatState {
-    // Normalize chat ID to prevent duplicates
-    // Always returns the ID in alphabetical order: "nodeA:nodeB"
-    fn normalize_chat_id(node1: &str, node2: &str) -> String {
-        if node1 < node2 {
-            format!("{}:{}", node1, node2)
-        } else {
-            format!("{}:{}", node2, node1)
-        }
+    // Channels the HTTP server opened on the public guest binding
+    fn is_public_channel(channel_id: u32) -> bool {
+        get_server()
+            .and_then(|server| server.get_ws_channels().get(PUBLIC_WS_PATH).cloned())
+            .map_or(false, |channels| channels.contains(&channel_id))
     }
 
-    async fn process_delivery_queue(&mut self) {
-        let queue_len = {
-            let queue = self.delivery_queue.lock().unwrap();
-            queue.len()
-        };
-        println!("Processing delivery queue with {} nodes", queue_len);
-
-        // Process queued messages for each node
-        let nodes_to_process: Vec<String> = {
-            let queue = self.delivery_queue.lock().unwrap();
-            queue.keys().cloned().collect()
-        };
-
-        for node in nodes_to_process {
-            // Get the first message for this node
-            let msg_to_send = {
-                let queue = self.delivery_queue.lock().unwrap();
-                queue.get(&node).and_then(|messages| messages.first().cloned())
-            };
-
-            if let Some(msg) = msg_to_send {
-                let target = Address::from((node.as_str(), OUR_PROCESS_ID));
-
-                // Try to send using generated RPC method
-                let msg_json = serde_json::to_value(&msg).unwrap();
-                let msg_for_rpc: CUChatMessage = serde_json::from_value(msg_json).unwrap();
-
-                match receive_message_remote_rpc(&target, msg_for_rpc.clone()).await {
-                    Ok(_) => {
-                        println!("Successfully delivered queued message {} to {}", msg.id, node);
-                        // Remove from queue if successful
-                        {
-                            let mut queue = self.delivery_queue.lock().unwrap();
-                            if let Some(node_queue) = queue.get_mut(&node) {
-                                node_queue.retain(|m| m.id != msg.id);
-                                if node_queue.is_empty() {
-                                    queue.remove(&node);
-                                }
-                            }
-                        }
-
-                        // Update message status in our chat
-                        for chat in self.chats.values_mut() {
-                            if let Some(message) = chat.messages.iter_mut().find(|m| m.id == msg.id) {
-                                message.status = safe_update_message_status(&message.status, MessageStatus::Sent);
-
-                                // Send ChatUpdate to WebSocket connections
-                                for &channel_id in self.ws_connections.keys() {
-                                    let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                                    send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                                        mime: Some("application/json".to_string()),
-                                        bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                                    });
-                                }
-                                break;
-                            }
-                        }
-                    }
+    fn handle_guest_ws(&mut self, channel_id: u32, message_type: WsMessageType, blob: LazyLoadBlob) {
+        match message_type {
+            WsMessageType::Close => {
+                println!("Guest WebSocket closed: {}", channel_id);
+                self.public_channels.remove(&channel_id);
+                self.guest_sessions.remove(&channel_id);
+            }
+            WsMessageType::Text => {
+                let msg = match serde_json::from_slice::<WsGuestMessage>(&blob.bytes) {
+                    Ok(msg) => msg,
                     Err(e) => {
-                        // Don't attempt more messages to this node if we get Offline or Timeout
-                        println!("Failed to deliver queued message to {}: {:?}", node, e);
+                        let error = WsServerMessage::Error {
+                            message: format!("Invalid message format: {}", e),
+                        };
+                        push_json(channel_id, &error);
+                        return;
                     }
+                };
+
+                // Nothing but AuthWithKey, Resume or WidgetStart is accepted until the channel has authenticated
+                let authenticated = self.guest_sessions.contains_key(&channel_id);
+                let reason = if !self.settings.allow_browser_chats {
+                    Some("Browser chats are disabled")
+                } else if !authenticated
+                    && !matches!(msg, WsGuestMessage::AuthWithKey { .. } | WsGuestMessage::Resume { .. } | WsGuestMessage::WidgetStart { .. }) {
+                    Some("Authenticate with AuthWithKey or Resume first")
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    let msg = WsServerMessage::AuthFailed { reason: reason.to_string() };
+                    push_json(channel_id, &msg);
+                    return;
                 }
+
+                self.handle_browser_message(channel_id, msg);
             }
+            WsMessageType::Binary | WsMessageType::Ping | WsMessageType::Pong => {}
         }
     }
 
@@ -2234,10 +6457,7 @@ impl ChatState {
         match msg {
             WsClientMessage::SendMessage { chat_id, content, reply_to } => {
                 // Create and send message
-                let timestamp = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
+                let timestamp = now_secs();
 
                 let message_id = format!("{}:{}", timestamp, rand::random::<u32>());
                 let sender = self.ws_connections.get(&channel_id)
@@ -2254,6 +6474,7 @@ impl ChatState {
                     reactions: Vec::new(),
                     message_type: MessageType::Text,
                     file_info: None,
+                    expires_at: self.message_expiry(&chat_id, timestamp),
                 };
 
                 // Add to chat
@@ -2268,10 +6489,7 @@ impl ChatState {
                         for (&ch_id, node) in &self.ws_connections {
                             if node == &counterparty {
                                 let msg = WsServerMessage::NewMessage(message.clone());
-                                send_ws_push(ch_id, WsMessageType::Text, LazyLoadBlob {
-                                    mime: Some("application/json".to_string()),
-                                    bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-                                });
+                                push_json(ch_id, &msg);
                                 break;
                             }
                         }
@@ -2292,18 +6510,12 @@ impl ChatState {
 
                     // Send ChatUpdate with the updated status
                     let chat_update = WsServerMessage::ChatUpdate(chat.clone());
-                    send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: serde_json::to_string(&chat_update).unwrap().into_bytes(),
-                    });
+                    push_json(channel_id, &chat_update);
                 }
 
                 // Send acknowledgment
                 let ack = WsServerMessage::MessageAck { message_id };
-                send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                    mime: Some("application/json".to_string()),
-                    bytes: serde_json::to_string(&ack).unwrap().into_bytes(),
-                });
+                push_json(channel_id, &ack);
             }
             WsClientMessage::Ack { message_id } => {
                 // Update message status
@@ -2335,114 +6547,230 @@ impl ChatState {
                     self.broadcast_to_all(serde_json::to_string(&msg).unwrap());
                 }
             }
+            WsClientMessage::GetChats(req) => {
+                let msg = WsServerMessage::ChatList(self.list_chats(&req));
+                push_json(channel_id, &msg);
+            }
+            WsClientMessage::GetLabels => {
+                let msg = WsServerMessage::LabelsUpdate(self.labels.clone());
+                push_json(channel_id, &msg);
+            }
+            // Successful label changes are broadcast by the helpers; only errors go back here
+            WsClientMessage::CreateLabel(req) => {
+                let result = self.add_label(req).map(|_| ());
+                Self::send_ws_result(channel_id, result);
+            }
+            WsClientMessage::UpdateLabel(req) => {
+                let result = self.edit_label(req).map(|_| ());
+                Self::send_ws_result(channel_id, result);
+            }
+            WsClientMessage::DeleteLabel(req) => {
+                let result = self.remove_label(&req.label_id);
+                Self::send_ws_result(channel_id, result);
+            }
+            WsClientMessage::SetChatLabels(req) => {
+                let result = self.apply_chat_labels(req).map(|_| ());
+                Self::send_ws_result(channel_id, result);
+            }
             WsClientMessage::Heartbeat => {
                 let msg = WsServerMessage::Heartbeat;
-                send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                    mime: Some("application/json".to_string()),
-                    bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-                });
-            }
-            _ => {
-                // Other message types not handled in node-to-node
+                push_json(channel_id, &msg);
             }
         }
     }
 
-    fn handle_browser_message(&mut self, channel_id: u32, msg: WsClientMessage) {
+    fn send_ws_result(channel_id: u32, result: Result<(), String>) {
+        if let Err(message) = result {
+            let error = WsServerMessage::Error { message };
+            push_json(channel_id, &error);
+        }
+    }
+
+    fn handle_browser_message(&mut self, channel_id: u32, msg: WsGuestMessage) {
         match msg {
-            WsClientMessage::AuthWithKey { chat_key } => {
-                if let Some(key_data) = self.chat_keys.get(&chat_key) {
+            WsGuestMessage::AuthWithKey { chat_key, device_id, passphrase, display_name } => {
+                if let Some(key_data) = self.chat_keys.get(&chat_key).cloned() {
                     if !key_data.is_revoked {
-                        // Store connection
-                        self.browser_connections.insert(chat_key.clone(), channel_id);
+                        if let Err(reason) = self.admit_guest(&chat_key, device_id.clone(), passphrase, display_name) {
+                            let msg = WsServerMessage::AuthFailed { reason };
+                            push_json(channel_id, &msg);
+                            return;
+                        }
+                        let key_data = self.chat_keys.get(&chat_key).cloned().unwrap_or(key_data);
+                        self.open_guest_session(channel_id, &key_data, device_id.clone());
 
                         // Get chat history
                         let history = self.chats.get(&key_data.chat_id)
-                            .map(|chat| chat.messages.clone())
+                            .map(|chat| chat.messages.iter().map(|m| self.guest_view(&chat_key, m)).collect())
                             .unwrap_or_default();
 
                         let msg = WsServerMessage::AuthSuccess {
                             chat_id: key_data.chat_id.clone(),
                             history,
+                            session_token: self.issue_resume_token(&chat_key, device_id),
                         };
-                        send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                    mime: Some("application/json".to_string()),
-                    bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-                });
+                        push_json(channel_id, &msg);
                     } else {
+                        let reason = if key_data.is_banned {
+                            "You have been removed from this chat"
+                        } else {
+                            "Chat key has been revoked"
+                        };
                         let msg = WsServerMessage::AuthFailed {
-                            reason: "Chat key has been revoked".to_string(),
+                            reason: reason.to_string(),
                         };
-                        send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                    mime: Some("application/json".to_string()),
-                    bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-                });
+                        push_json(channel_id, &msg);
                     }
                 } else {
                     let msg = WsServerMessage::AuthFailed {
                         reason: "Invalid chat key".to_string(),
                     };
-                    send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                    mime: Some("application/json".to_string()),
-                    bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-                });
+                    push_json(channel_id, &msg);
                 }
             }
-            WsClientMessage::BrowserMessage { content } => {
-                // Find chat key for this connection
-                if let Some((chat_key, _)) = self.browser_connections.iter().find(|(_, &ch)| ch == channel_id) {
-                    if let Some(key_data) = self.chat_keys.get(chat_key) {
-                        let timestamp = std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs();
-
-                        let message = ChatMessage {
-                            id: format!("{}:{}", timestamp, rand::random::<u32>()),
-                            sender: key_data.user_name.clone(),
-                            content,
-                            timestamp,
-                            status: MessageStatus::Sent,
-                            reply_to: None,
-                            reactions: Vec::new(),
-                            message_type: MessageType::Text,
-                            file_info: None,
+            WsGuestMessage::Resume { session_token, since_message_id } => {
+                let now = now_secs();
+
+                // Tokens die with their link, so revoking, banning or expiring it also ends resumption
+                let resumable = self.resume_tokens.get(&session_token)
+                    .filter(|token| now < token.issued_at + GUEST_RESUME_TOKEN_TTL_SECS)
+                    .and_then(|token| self.chat_keys.get(&token.chat_key).map(|key| (key.clone(), token.device_id.clone())))
+                    .filter(|(key, _)| key.is_usable(now));
+                let (key_data, device_id) = match resumable {
+                    Some(resumable) => resumable,
+                    None => {
+                        self.resume_tokens.remove(&session_token);
+                        let msg = WsServerMessage::AuthFailed {
+                            reason: "Session expired".to_string(),
                         };
+                        push_json(channel_id, &msg);
+                        return;
+                    }
+                };
+                self.open_guest_session(channel_id, &key_data, device_id);
+
+                // Only what came after the guest's last message. If that message is gone, or any
+                // message the tab already has was edited, deleted or reacted to since it last
+                // synced, we can't patch its view, so send everything.
+                let synced_at = self.resume_tokens.get(&session_token).map_or(0, |token| token.synced_at);
+                let history_changed = self.history_changed_at.get(&key_data.chat_id)
+                    .map_or(false, |&changed_at| changed_at >= synced_at);
+                let (messages, full_history) = match self.chats.get(&key_data.chat_id) {
+                    Some(chat) => {
+                        let start = since_message_id.as_ref()
+                            .filter(|_| !history_changed)
+                            .and_then(|id| chat.messages.iter().position(|m| &m.id == id))
+                            .map(|pos| pos + 1);
+                        let missed = chat.messages[start.unwrap_or(0)..].iter()
+                            .map(|m| self.guest_view(&key_data.key, m))
+                            .collect();
+                        (missed, start.is_none())
+                    }
+                    None => (Vec::new(), true),
+                };
+                if let Some(token) = self.resume_tokens.get_mut(&session_token) {
+                    token.synced_at = now;
+                }
 
-                        // Add to chat
-                        let chat = self.chats.entry(key_data.chat_id.clone())
-                            .or_insert_with(|| Chat {
-                                id: key_data.chat_id.clone(),
-                                counterparty: key_data.user_name.clone(),
-                                messages: Vec::new(),
-                                last_activity: timestamp,
-                                unread_count: 0,
-                                is_blocked: false,
-                                notify: true,
-                                counterparty_profile: None,
-                            });
-
-                        chat.messages.push(message.clone());
-                        chat.last_activity = timestamp;
-                        chat.unread_count += 1;
-
-                        // Send message to all participants
-                        let msg = WsServerMessage::NewMessage(message);
-                        send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                    mime: Some("application/json".to_string()),
-                    bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-                });
+                let msg = WsServerMessage::Resumed {
+                    chat_id: key_data.chat_id.clone(),
+                    messages,
+                    full_history,
+                };
+                push_json(channel_id, &msg);
+            }
+            WsGuestMessage::WidgetStart { ticket, device_id, name, email } => {
+                // A tab already in a chat doesn't get to open more of them
+                if self.guest_sessions.contains_key(&channel_id) {
+                    let error = WsServerMessage::Error {
+                        message: "This window is already connected to a chat".to_string(),
+                    };
+                    push_json(channel_id, &error);
+                    return;
+                }
+
+                match self.provision_widget_key(&ticket, device_id.clone(), name, email) {
+                    Ok(chat_key) => {
+                        let msg = WsServerMessage::WidgetStarted { chat_key: chat_key.clone() };
+                        push_json(channel_id, &msg);
+                        // From here on the visitor is an ordinary guest on their own link
+                        self.handle_browser_message(channel_id, WsGuestMessage::AuthWithKey {
+                            chat_key,
+                            device_id,
+                            passphrase: None,
+                            display_name: None,
+                        });
+                    }
+                    Err(reason) => {
+                        let msg = WsServerMessage::AuthFailed { reason };
+                        push_json(channel_id, &msg);
                     }
                 }
             }
-            WsClientMessage::Heartbeat => {
+            WsGuestMessage::RequestUpgrade { node } => {
+                let key_data = match self.guest_key_for_channel(channel_id) {
+                    Some(key_data) => key_data,
+                    None => return,
+                };
+
+                let msg = match self.start_guest_upgrade(&key_data, &node) {
+                    Ok(msg) => msg,
+                    Err(message) => WsServerMessage::Error { message },
+                };
+                push_json(channel_id, &msg);
+            }
+            WsGuestMessage::BrowserMessage { content } => {
+                let key_data = match self.guest_key_for_channel(channel_id) {
+                    Some(key_data) => key_data,
+                    None => return,
+                };
+                if !self.check_guest_rate(channel_id, &key_data) {
+                    return;
+                }
+
+                let timestamp = now_secs();
+
+                // The guest's message is on our node as soon as we store it
+                let message = ChatMessage {
+                    id: format!("{}:{}", timestamp, rand::random::<u32>()),
+                    sender: key_data.user_name.clone(),
+                    content,
+                    timestamp,
+                    status: MessageStatus::Delivered,
+                    reply_to: None,
+                    reactions: Vec::new(),
+                    message_type: MessageType::Text,
+                    file_info: None,
+                    expires_at: self.message_expiry(&key_data.chat_id, timestamp),
+                };
+
+                self.record_guest_activity(channel_id, &message);
+                self.accept_guest_message(&key_data, message);
+            }
+            WsGuestMessage::SendFile { filename, mime_type, data } => {
+                let key_data = match self.guest_key_for_channel(channel_id) {
+                    Some(key_data) => key_data,
+                    None => return,
+                };
+                if !self.check_guest_rate(channel_id, &key_data) {
+                    return;
+                }
+
+                match self.store_guest_file(&key_data, &filename, &mime_type, &data) {
+                    Ok(message) => {
+                        self.record_guest_activity(channel_id, &message);
+                        self.accept_guest_message(&key_data, message);
+                    }
+                    Err(message) => {
+                        let error = WsServerMessage::Error { message };
+                        push_json(channel_id, &error);
+                    }
+                }
+            }
+            WsGuestMessage::Heartbeat => {
                 let msg = WsServerMessage::Heartbeat;
-                send_ws_push(channel_id, WsMessageType::Text, LazyLoadBlob {
-                    mime: Some("application/json".to_string()),
-                    bytes: serde_json::to_string(&msg).unwrap().into_bytes(),
-                });
+                push_json(channel_id, &msg);
             }
-            _ => {}
         }
     }
 
@@ -2548,3 +6876,185 @@ mod arc_mutex_serde {
         Ok(Arc::new(Mutex::new(data)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_message(id: &str, timestamp: u64) -> ChatMessage {
+        ChatMessage {
+            id: id.to_string(),
+            sender: "alice.os".to_string(),
+            content: format!("message {}", id),
+            timestamp,
+            status: MessageStatus::Delivered,
+            reply_to: None,
+            reactions: Vec::new(),
+            message_type: MessageType::Text,
+            file_info: None,
+            expires_at: None,
+        }
+    }
+
+    fn state_with_secret() -> ChatState {
+        ChatState {
+            url_signing_secret: "test-secret".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_byte_range_handles_open_suffix_and_bad_ranges() {
+        assert_eq!(parse_byte_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_byte_range("bytes=900-", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_byte_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_byte_range("bytes=500-5000", 1000), Ok(Some((500, 999))));
+        assert_eq!(parse_byte_range("bytes=0-1,5-9", 1000), Ok(None));
+        assert_eq!(parse_byte_range("items=0-1", 1000), Ok(None));
+        assert_eq!(parse_byte_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_byte_range("bytes=9-5", 1000), Err(()));
+        assert_eq!(parse_byte_range("bytes=-0", 1000), Err(()));
+    }
+
+    #[test]
+    fn content_disposition_downloads_scriptable_types_and_encodes_names() {
+        assert!(content_disposition("a.png", "image/png").starts_with("inline;"));
+        assert!(content_disposition("a.svg", "image/svg+xml").starts_with("attachment;"));
+        assert!(content_disposition("a.html", "text/html").starts_with("attachment;"));
+        assert_eq!(
+            content_disposition("résumé \"v2\".pdf", "application/pdf"),
+            "inline; filename=\"r_sum_ _v2_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%20%22v2%22.pdf",
+        );
+    }
+
+    #[test]
+    fn decompress_data_refuses_output_over_the_cap() {
+        let compressed = compress_data(&[7u8; 4096]).unwrap();
+        assert_eq!(decompress_data(&compressed, 4096).unwrap().len(), 4096);
+        assert!(decompress_data(&compressed, 4095).is_err());
+    }
+
+    #[test]
+    fn retention_keeps_pinned_and_starred_messages() {
+        let mut state = ChatState::default();
+        let mut chat = Chat::new("a:b".to_string(), "b".to_string(), 4);
+        chat.messages = (1..=4).map(|i| text_message(&i.to_string(), i)).collect();
+        chat.pinned_messages.push(PinnedMessage { message_id: "1".to_string(), pinned_by: "b".to_string(), pinned_at: 5 });
+        chat.retention = Some(RetentionPolicy { max_age_days: None, max_messages: Some(1), attachments_only: false });
+        state.chats.insert(chat.id.clone(), chat);
+        state.starred_messages.push(StarredMessage { chat_id: "a:b".to_string(), message_id: "2".to_string(), starred_at: 5 });
+
+        assert_eq!(state.apply_retention(), 1);
+        let kept: Vec<&str> = state.chats["a:b"].messages.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(kept, vec!["1", "2", "4"]);
+        assert!(state.history_changed_at.contains_key("a:b"));
+    }
+
+    #[test]
+    fn timer_change_last_writer_wins_with_name_tie_break() {
+        assert!(timer_change_wins(10, 11, "a.os", "b.os"));
+        assert!(!timer_change_wins(10, 9, "z.os", "b.os"));
+        assert!(timer_change_wins(10, 10, "c.os", "b.os"));
+        assert!(!timer_change_wins(10, 10, "a.os", "b.os"));
+    }
+
+    #[test]
+    fn format_duration_picks_the_largest_whole_unit() {
+        assert_eq!(format_duration(60), "1 minute");
+        assert_eq!(format_duration(90), "90 seconds");
+        assert_eq!(format_duration(24 * 60 * 60), "1 day");
+        assert_eq!(format_duration(14 * 24 * 60 * 60), "2 weeks");
+    }
+
+    #[test]
+    fn apply_pin_reports_only_real_changes() {
+        let mut chat = Chat::new("a:b".to_string(), "b".to_string(), 0);
+        assert!(ChatState::apply_pin(&mut chat, "m1", true, "b", 1));
+        assert!(!ChatState::apply_pin(&mut chat, "m1", true, "a", 2));
+        assert_eq!(chat.pinned_messages[0].pinned_by, "b");
+        assert!(ChatState::apply_pin(&mut chat, "m1", false, "a", 3));
+        assert!(!ChatState::apply_pin(&mut chat, "m1", false, "a", 4));
+    }
+
+    #[test]
+    fn list_chats_hides_archived_and_requests_by_default() {
+        let mut state = ChatState::default();
+        let inbox = Chat::new("inbox".to_string(), "a".to_string(), 3);
+        let archived = Chat { is_archived: true, ..Chat::new("archived".to_string(), "b".to_string(), 2) };
+        let request = Chat { is_request: true, ..Chat::new("request".to_string(), "c".to_string(), 1) };
+        for chat in [inbox, archived, request] {
+            state.chats.insert(chat.id.clone(), chat);
+        }
+        let ids = |req: GetChatsReq| -> Vec<String> {
+            state.list_chats(&req).into_iter().map(|chat| chat.id).collect()
+        };
+
+        assert_eq!(ids(GetChatsReq::default()), vec!["inbox"]);
+        assert_eq!(ids(GetChatsReq { archived: Some(true), ..Default::default() }), vec!["archived"]);
+        assert_eq!(ids(GetChatsReq { requests: Some(true), ..Default::default() }), vec!["request"]);
+        assert!(ids(GetChatsReq { unread_only: true, ..Default::default() }).is_empty());
+    }
+
+    #[test]
+    fn rate_bucket_refills_up_to_capacity() {
+        let kind = InboundKind::Message;
+        let (capacity, per_second) = kind.limits();
+        let mut bucket = RateBucket::new(kind, 1_000);
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.retry_after_ms(kind), (1000.0 / per_second).ceil() as u64);
+
+        bucket.refill(kind, 2_000);
+        assert_eq!(bucket.tokens, per_second);
+        bucket.refill(kind, 1_000_000);
+        assert_eq!(bucket.tokens, capacity);
+        assert_eq!(bucket.retry_after_ms(kind), 0);
+    }
+
+    #[test]
+    fn quiet_hours_overnight_window_belongs_to_its_start_day() {
+        // 1970-01-05 was a Monday
+        let monday = 4 * 24 * 60 * 60;
+        let window = QuietHours { start_minute: 22 * 60, end_minute: 7 * 60, days: vec![1] };
+        assert!(in_daily_windows(&[window.clone()], 0, monday + 23 * 60 * 60));
+        assert!(in_daily_windows(&[window.clone()], 0, monday + 30 * 60 * 60));
+        assert!(!in_daily_windows(&[window.clone()], 0, monday + 12 * 60 * 60));
+        assert!(!in_daily_windows(&[window], 0, monday - 60 * 60));
+    }
+
+    #[test]
+    fn passphrases_verify_salted_and_legacy_hashes() {
+        let stored = hash_passphrase("key", "open sesame");
+        assert!(verify_passphrase("key", "open sesame", &stored));
+        assert!(!verify_passphrase("key", "open sesame!", &stored));
+        assert!(!verify_passphrase("other", "open sesame", &stored));
+
+        let legacy = format!("{:x}", Sha256::digest(b"keyopen sesame"));
+        assert!(verify_passphrase("key", "open sesame", &legacy));
+        assert!(!verify_passphrase("key", "wrong", &legacy));
+    }
+
+    #[test]
+    fn guest_file_signatures_are_bound_to_key_file_and_expiry() {
+        let state = state_with_secret();
+        let signature = state.sign_guest_file("key", "file", 100);
+        assert!(state.verify_guest_file("key", "file", 100, &signature));
+        assert!(!state.verify_guest_file("key", "file", 101, &signature));
+        assert!(!state.verify_guest_file("other", "file", 100, &signature));
+        assert!(!state.verify_guest_file("key", "file", 100, "not hex"));
+        assert!(!ChatState::default().verify_guest_file("key", "file", 100, &signature));
+    }
+
+    #[test]
+    fn widget_tickets_expire_and_resist_tampering() {
+        let state = state_with_secret();
+        let ticket = state.widget_ticket(100);
+        assert!(state.verify_widget_ticket(&ticket, 99));
+        assert!(!state.verify_widget_ticket(&ticket, 100));
+
+        let (_, signature) = ticket.split_once('.').unwrap();
+        assert!(!state.verify_widget_ticket(&format!("200.{}", signature), 99));
+        assert!(!state.verify_widget_ticket("100", 99));
+        assert!(!state.verify_widget_ticket("soon.abcd", 99));
+        assert!(!ChatState::default().verify_widget_ticket(&ticket, 99));
+    }
+}
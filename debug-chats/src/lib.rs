@@ -49,7 +49,7 @@ fn init(our: Address, args: String) -> String {
 
 fn get_chats(chat_address: &Address) -> String {
     let request = serde_json::json!({
-        "GetChats": null
+        "GetChats": {}
     });
 
     match Request::to(chat_address)
@@ -0,0 +1,63 @@
+// Embeddable chat widget:
+// <script src="https://<node>/chat:chat:ware.hypr/public/widget.js" async></script>
+(function () {
+    const script = document.currentScript;
+    if (!script || window.__hyperwareChatWidget) return;
+    window.__hyperwareChatWidget = true;
+
+    const base = script.src.split('/public/widget.js')[0];
+
+    const frame = document.createElement('iframe');
+    frame.src = `${base}/public/widget`;
+    frame.title = 'Chat';
+    frame.style.cssText = [
+        'position: fixed',
+        'bottom: 90px',
+        'right: 20px',
+        'width: 360px',
+        'height: 520px',
+        'max-width: calc(100vw - 40px)',
+        'max-height: calc(100vh - 110px)',
+        'border: none',
+        'border-radius: 12px',
+        'box-shadow: 0 8px 30px rgba(0, 0, 0, 0.2)',
+        'z-index: 2147483646',
+        'display: none',
+    ].join(';');
+
+    const button = document.createElement('button');
+    button.type = 'button';
+    button.setAttribute('aria-label', 'Open chat');
+    button.textContent = '💬';
+    button.style.cssText = [
+        'position: fixed',
+        'bottom: 20px',
+        'right: 20px',
+        'width: 56px',
+        'height: 56px',
+        'border: none',
+        'border-radius: 50%',
+        'background: #007aff',
+        'color: white',
+        'font-size: 26px',
+        'cursor: pointer',
+        'box-shadow: 0 4px 14px rgba(0, 0, 0, 0.25)',
+        'z-index: 2147483647',
+    ].join(';');
+
+    button.addEventListener('click', () => {
+        const open = frame.style.display === 'none';
+        frame.style.display = open ? 'block' : 'none';
+        button.setAttribute('aria-label', open ? 'Close chat' : 'Open chat');
+    });
+
+    const mount = () => {
+        document.body.appendChild(frame);
+        document.body.appendChild(button);
+    };
+    if (document.body) {
+        mount();
+    } else {
+        document.addEventListener('DOMContentLoaded', mount);
+    }
+})();
@@ -52,7 +52,8 @@ const FileUpload: React.FC<FileUploadProps> = ({ onClose }) => {
                 filename: file.name,
                 mime_type: file.type || 'application/octet-stream',
                 data: base64,
-                reply_to: null
+                reply_to: null,
+                send_at: null
               });
               
               // Mark as complete
@@ -1,5 +1,5 @@
 import React, { useState } from 'react';
-import { create_chat_link } from '../../../../target/ui/caller-utils';
+import { create_chat_link, get_qr_code } from '../../../../target/ui/caller-utils';
 import { useChatStore } from '../../store/chat';
 import './CreateChatLink.css';
 
@@ -11,6 +11,7 @@ export const CreateChatLink: React.FC<CreateChatLinkProps> = ({ onClose }) => {
   const { activeChat } = useChatStore();
   const [singleUse, setSingleUse] = useState(false);
   const [generatedLink, setGeneratedLink] = useState<string | null>(null);
+  const [qrCode, setQrCode] = useState<string | null>(null);
   const [loading, setLoading] = useState(false);
   const [error, setError] = useState<string | null>(null);
 
@@ -24,10 +25,27 @@ export const CreateChatLink: React.FC<CreateChatLinkProps> = ({ onClose }) => {
       }
       const link = await create_chat_link({ 
         chat_id: activeChat.id,
-        single_use: singleUse 
+        single_use: singleUse,
+        expires_in_seconds: null,
+        max_uses: null,
+        passphrase: null,
+        guest_name: null,
       });
-      const fullLink = `${window.location.origin}/public/join-${link}`;
-      setGeneratedLink(fullLink);
+      setGeneratedLink(link);
+
+      // Easier to share in person than the URL itself
+      try {
+        const qr = await get_qr_code({
+          link,
+          format: 'Svg',
+          size: 256,
+          error_correction: 'Quartile',
+          with_icon: true,
+        });
+        setQrCode(qr.data_url);
+      } catch (err) {
+        console.error('Error rendering QR code:', err);
+      }
     } catch (err) {
       setError('Failed to create chat link');
       console.error('Error creating chat link:', err);
@@ -84,9 +102,15 @@ export const CreateChatLink: React.FC<CreateChatLinkProps> = ({ onClose }) => {
               />
               <button onClick={handleCopyLink}>Copy</button>
             </div>
+
+            {qrCode && (
+              <div className="qr-code">
+                <img src={qrCode} alt="QR code for the chat link" width={256} height={256} />
+              </div>
+            )}
             
             <div className="button-group">
-              <button onClick={() => setGeneratedLink(null)}>
+              <button onClick={() => { setGeneratedLink(null); setQrCode(null); }}>
                 Create Another
               </button>
               <button onClick={onClose}>
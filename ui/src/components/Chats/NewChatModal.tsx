@@ -8,12 +8,17 @@ interface NewChatModalProps {
 
 const NewChatModal: React.FC<NewChatModalProps> = ({ onClose }) => {
   const [counterparty, setCounterparty] = useState('');
-  const { createChat } = useChatStore();
+  const [claimCode, setClaimCode] = useState('');
+  const { createChat, claimGuestChat } = useChatStore();
 
   const handleSubmit = async (e: React.FormEvent) => {
     e.preventDefault();
     if (counterparty.trim()) {
-      await createChat(counterparty.trim());
+      if (claimCode.trim()) {
+        await claimGuestChat(counterparty.trim(), claimCode.trim());
+      } else {
+        await createChat(counterparty.trim());
+      }
       onClose();
     }
   };
@@ -38,6 +43,17 @@ const NewChatModal: React.FC<NewChatModalProps> = ({ onClose }) => {
               autoFocus
             />
           </div>
+
+          <div className="form-group">
+            <label htmlFor="claimCode">Claim code (optional):</label>
+            <input
+              type="text"
+              id="claimCode"
+              value={claimCode}
+              onChange={(e) => setClaimCode(e.target.value)}
+              placeholder="From a chat you started as a guest"
+            />
+          </div>
           
           <div className="modal-actions">
             <button type="button" onClick={onClose} className="cancel-button">
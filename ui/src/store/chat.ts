@@ -39,6 +39,7 @@ interface ChatStore {
   loadProfile: () => Promise<void>;
   loadSettings: () => Promise<void>;
   createChat: (counterparty: string) => Promise<void>;
+  claimGuestChat: (host: string, challenge: string) => Promise<void>;
   sendMessage: (chatId: string, content: string, replyTo?: string) => Promise<void>;
   editMessage: (messageId: string, newContent: string) => Promise<void>;
   deleteMessage: (messageId: string) => Promise<void>;
@@ -120,6 +121,33 @@ export const useChatStore = create<ChatStore>((set, get) => ({
     stt_enabled: false,
     stt_api_key: null,
     max_file_size_mb: 10,
+    inbound_quota_mb: 500,
+    retention: { max_age_days: null, max_messages: null, attachments_only: false },
+    undo_send_seconds: 0,
+    keep_archived: false,
+    message_requests: false,
+    notification_rules: {
+      quiet_hours: [],
+      tz_offset_minutes: -new Date().getTimezoneOffset(),
+      keywords: [],
+      hide_preview: false,
+      contact_overrides: [],
+      coalesce_window_seconds: 60,
+      digest_interval_minutes: 0,
+    },
+    public_base_url: null,
+    guest_upload_quota_mb: 50,
+    widget: {
+      enabled: false,
+      allowed_origins: [],
+      title: null,
+      greeting: null,
+      ask_name: false,
+      ask_email: false,
+      office_hours: [],
+      tz_offset_minutes: -new Date().getTimezoneOffset(),
+      away_message: null,
+    },
   },
   chatKeys: [],
   wsConnection: null,
@@ -253,7 +281,7 @@ export const useChatStore = create<ChatStore>((set, get) => ({
   loadChatsFromServer: async () => {
     try {
       console.log('[SYNC] Loading chats from server...');
-      const chats = await api.get_chats();
+      const chats = await api.get_chats({ label: null, unread_only: false, archived: false, blocked: null, requests: false, sort: null });
       console.log('[SYNC] Loaded', chats.length, 'chats from server');
       
       set({ chats });
@@ -280,7 +308,7 @@ export const useChatStore = create<ChatStore>((set, get) => ({
       console.log('[SYNC] Syncing with server...');
       
       // Fetch all chats from server (complete history)
-      const serverChats = await api.get_chats();
+      const serverChats = await api.get_chats({ label: null, unread_only: false, archived: false, blocked: null, requests: false, sort: null });
       console.log('[SYNC] Got', serverChats.length, 'chats from server');
       
       // Update state with server data
@@ -439,6 +467,23 @@ export const useChatStore = create<ChatStore>((set, get) => ({
     }
   },
 
+  // Take over a conversation we started as a guest on the host's chat link
+  claimGuestChat: async (host: string, challenge: string) => {
+    try {
+      set({ isLoading: true });
+      const chat = await api.claim_guest_chat({ host, challenge });
+
+      set(state => ({
+        chats: [chat, ...state.chats.filter(c => c.id !== chat.id)],
+        activeChat: chat,
+      }));
+    } catch (error) {
+      set({ error: error instanceof Error ? error.message : 'Failed to claim guest chat' });
+    } finally {
+      set({ isLoading: false });
+    }
+  },
+
   // Send a message
   sendMessage: async (chatId: string, content: string, replyTo?: string) => {
     // Create optimistic message immediately
@@ -498,7 +543,8 @@ export const useChatStore = create<ChatStore>((set, get) => ({
         chat_id: chatId, 
         content, 
         reply_to: replyTo || null,
-        file_info: null
+        file_info: null,
+        send_at: null
       });
       
       console.log('[SEND] Received real message from API:', message.id, 'replacing temp:', tempId);
@@ -1030,6 +1076,16 @@ export const useChatStore = create<ChatStore>((set, get) => ({
           activeChat: updatedActiveChat
         }
       });
+    } else if (message.ChatMerged) {
+      // A guest chat moved into a 1:1 chat, which arrives separately as a ChatUpdate
+      const { from_chat_id, into_chat_id } = message.ChatMerged;
+      set(state => {
+        const chats = state.chats.filter(c => c.id !== from_chat_id);
+        const activeChat = state.activeChat?.id === from_chat_id
+          ? chats.find(c => c.id === into_chat_id) || null
+          : state.activeChat;
+        return { chats, activeChat };
+      });
     } else if (message.StatusUpdate) {
       // Handle status updates
       set({ connectionStatus: 'connected' });
@@ -1047,7 +1103,11 @@ export const useChatStore = create<ChatStore>((set, get) => ({
       
       return await api.create_chat_link({ 
         chat_id: chatId,
-        single_use: singleUse 
+        single_use: singleUse,
+        expires_in_seconds: null,
+        max_uses: null,
+        passphrase: null,
+        guest_name: null,
       });
     } catch (error) {
       set({ error: 'Failed to create chat link' });
@@ -8,27 +8,57 @@ export type {
   MessageStatus
 } from '../../../target/ui/caller-utils';
 
+import type { GetChatsReq, CreateLabelReq, UpdateLabelReq, DeleteLabelReq, SetChatLabelsReq } from '../../../target/ui/caller-utils';
+
 // Additional frontend-specific types
 export interface WsClientMessage {
   SendMessage?: { chat_id: string; content: string; reply_to?: string };
   Ack?: { message_id: string };
   MarkRead?: { chat_id: string };
   UpdateStatus?: { status: string };
-  AuthWithKey?: { chat_key: string };
+  GetChats?: Partial<GetChatsReq>;
+  GetLabels?: null;
+  CreateLabel?: CreateLabelReq;
+  UpdateLabel?: UpdateLabelReq;
+  DeleteLabel?: DeleteLabelReq;
+  SetChatLabels?: SetChatLabelsReq;
+  Heartbeat?: null;
+}
+
+// Messages accepted on the public guest socket (/public-ws)
+export interface WsGuestMessage {
+  AuthWithKey?: { chat_key: string; device_id?: string; passphrase?: string; display_name?: string };
   BrowserMessage?: { content: string };
+  SendFile?: { filename: string; mime_type: string; data: string };
+  Resume?: { session_token: string; since_message_id?: string };
+  WidgetStart?: { ticket: string; device_id?: string; name?: string; email?: string };
+  RequestUpgrade?: { node: string };
   Heartbeat?: null;
 }
 
-import type { ChatMessage, Chat, UserProfile } from '../../../target/ui/caller-utils';
+import type { ChatMessage, Chat, UserProfile, ScheduledMessage, StarredMessage, Label, Contact } from '../../../target/ui/caller-utils';
 
 export interface WsServerMessage {
   NewMessage?: ChatMessage;
   MessageAck?: { message_id: string };
   StatusUpdate?: { node: string; status: string };
   ChatUpdate?: Chat;
+  ChatList?: Chat[];
+  LabelsUpdate?: Label[];
+  ContactsUpdate?: Contact[];
+  StarredUpdate?: StarredMessage[];
   ProfileUpdate?: { node: string; profile: UserProfile };
-  AuthSuccess?: { chat_id: string; history: ChatMessage[] };
+  ScheduledMessagesUpdate?: ScheduledMessage[];
+  ScheduledMessageSent?: { chat_id: string; message: ChatMessage };
+  ScheduledMessageFailed?: { id: string; chat_id: string; error: string };
+  AuthSuccess?: { chat_id: string; history: ChatMessage[]; session_token: string };
   AuthFailed?: { reason: string };
+  Resumed?: { chat_id: string; messages: ChatMessage[]; full_history: boolean };
+  Kicked?: { reason: string };
+  WidgetStarted?: { chat_key: string };
+  UpgradeChallenge?: { host: string; node: string; challenge: string; expires_at: number };
+  Upgraded?: { node: string; chat_id: string };
+  ChatMerged?: { from_chat_id: string; into_chat_id: string };
   Heartbeat?: null;
   Error?: { message: string };
 }
\ No newline at end of file
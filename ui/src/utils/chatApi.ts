@@ -2,6 +2,7 @@
 export {
   create_chat,
   create_chat_link,
+  claim_guest_chat,
   delete_chat,
   delete_message,
   edit_message,